@@ -0,0 +1,6 @@
+// links the kernel (and every test kernel) with linker.ld, see memory::kernel_sections
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg=-T{}/linker.ld", manifest_dir);
+    println!("cargo:rerun-if-changed=linker.ld");
+}
@@ -0,0 +1,115 @@
+// How often growing a Vec one push at a time has to copy its contents, with the allocators' own
+// realloc versus the default GlobalAlloc::realloc (always allocate, copy, free).
+//
+//     cargo bench
+
+use allocator_host_tests::allocator::{
+    fixed_size_block::FixedSizeBlockAllocator, linked_list::LinkedListAllocator, Locked,
+};
+use allocator_host_tests::Arena;
+use std::alloc::{GlobalAlloc, Layout};
+use std::time::{Duration, Instant};
+
+const ARENA_SIZE: usize = 16 * 1024 * 1024;
+const ELEMENTS: usize = 100_000;
+const ROUNDS: usize = 20;
+
+// forwards alloc and dealloc only, so realloc is the trait's default
+struct DefaultRealloc<A>(A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DefaultRealloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+}
+
+#[derive(Default)]
+struct Run {
+    reallocs: usize,
+    copies: usize,
+    bytes_copied: usize,
+    time: Duration,
+}
+
+// grows `vecs` Vec<u64>s side by side the way RawVec does: capacity 4, then doubling
+fn push_pattern<A: GlobalAlloc>(allocator: &A, vecs: usize) -> Run {
+    let mut result = Run::default();
+    let start = Instant::now();
+    let mut buffers: Vec<(*mut u8, usize)> = vec![(std::ptr::null_mut(), 0); vecs];
+    for len in 0..ELEMENTS {
+        for (ptr, capacity) in buffers.iter_mut() {
+            if len == *capacity {
+                let new_capacity = (*capacity * 2).max(4);
+                let new_size = new_capacity * 8;
+                let new_ptr = unsafe {
+                    if *capacity == 0 {
+                        allocator.alloc(Layout::from_size_align(new_size, 8).unwrap())
+                    } else {
+                        result.reallocs += 1;
+                        allocator.realloc(*ptr, Layout::from_size_align(*capacity * 8, 8).unwrap(), new_size)
+                    }
+                };
+                assert!(!new_ptr.is_null(), "arena too small");
+                if *capacity > 0 && new_ptr != *ptr {
+                    result.copies += 1;
+                    result.bytes_copied += len * 8;
+                }
+                *ptr = new_ptr;
+                *capacity = new_capacity;
+            }
+            unsafe { (*ptr as *mut u64).add(len).write(len as u64) };
+        }
+    }
+    for (ptr, capacity) in buffers {
+        unsafe { allocator.dealloc(ptr, Layout::from_size_align(capacity * 8, 8).unwrap()) };
+    }
+    result.time = start.elapsed();
+    result
+}
+
+fn bench<A: GlobalAlloc>(name: &str, vecs: usize, new_allocator: impl Fn(&Arena) -> A) {
+    let mut total = Run::default();
+    for _ in 0..ROUNDS {
+        let arena = Arena::new(ARENA_SIZE, 4096);
+        let result = push_pattern(&new_allocator(&arena), vecs);
+        total.reallocs += result.reallocs;
+        total.copies += result.copies;
+        total.bytes_copied += result.bytes_copied;
+        total.time += result.time;
+    }
+    println!(
+        "{:<40} {:>2} vec(s): {:>4} reallocs, {:>4} copied ({:>9} bytes), {:>8.1?} per round",
+        name,
+        vecs,
+        total.reallocs / ROUNDS,
+        total.copies / ROUNDS,
+        total.bytes_copied / ROUNDS,
+        total.time / ROUNDS as u32
+    );
+}
+
+fn linked_list(arena: &Arena) -> Locked<LinkedListAllocator> {
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    allocator
+}
+
+fn fixed_size_block(arena: &Arena) -> Locked<FixedSizeBlockAllocator> {
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    allocator
+}
+
+fn main() {
+    // one Vec growing on its own, like the loop in main.rs, then two growing side by side
+    for &vecs in &[1, 2] {
+        bench("LinkedListAllocator, default realloc", vecs, |arena| DefaultRealloc(linked_list(arena)));
+        bench("LinkedListAllocator", vecs, linked_list);
+        bench("FixedSizeBlockAllocator, default realloc", vecs, |arena| DefaultRealloc(fixed_size_block(arena)));
+        bench("FixedSizeBlockAllocator", vecs, fixed_size_block);
+    }
+}
@@ -0,0 +1,5 @@
+// lets the allocator sources leave out what only builds with a nightly compiler (like the Allocator trait)
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(host_tests)");
+    println!("cargo:rustc-cfg=host_tests");
+}
@@ -0,0 +1,36 @@
+// the allocator modules from the kernel, with the parts of src/allocator.rs they rely on.
+// paths are relative to host-tests/src/
+
+use alloc::alloc::Layout;
+
+#[path = "../../src/allocator/common.rs"]
+mod common;
+pub use common::{ExtendHeap, Locked};
+use common::{align_up, realloc_by_moving};
+
+#[path = "../../src/allocator/arena.rs"]
+pub mod arena;
+#[path = "../../src/allocator/bump.rs"]
+pub mod bump;
+#[path = "../../src/allocator/buddy.rs"]
+pub mod buddy;
+#[path = "../../src/allocator/linked_list.rs"]
+pub mod linked_list;
+#[path = "../../src/allocator/fixed_size_block.rs"]
+pub mod fixed_size_block;
+#[path = "../../src/allocator/slab.rs"]
+pub mod slab;
+#[path = "../../src/allocator/stats.rs"]
+pub mod stats;
+
+pub use stats::{HeapStatistics, HeapStats};
+
+// there's no page table to map more heap pages into on the host, an arena is all an allocator gets
+fn grow_heap<A: ExtendHeap>(_allocator: &mut A, _layout: &Layout) -> bool {
+    false
+}
+
+// and no reclaim callback to free memory when that runs out
+fn retry_after_reclaim(_layout: &Layout, mut alloc: impl FnMut() -> *mut u8) -> *mut u8 {
+    alloc()
+}
@@ -0,0 +1,65 @@
+//! The kernel's heap allocators built for the host, on top of memory from the host's own allocator.
+//!
+//! The allocator sources are shared with the kernel through `#[path]`, see `allocator.rs`.
+
+extern crate alloc;
+
+pub mod allocator;
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+
+/// A block of host memory for one of the allocators to manage, freed when dropped.
+pub struct Arena {
+    start: *mut u8,
+    layout: Layout,
+}
+
+impl Arena {
+    /// `align` has to be a power of two, the buddy allocator needs arenas aligned to their size
+    /// to keep everything in a single block.
+    pub fn new(size: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let start = unsafe { alloc_zeroed(layout) };
+        assert!(!start.is_null(), "host allocation for the arena failed");
+        Arena { start, layout }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn end(&self) -> usize {
+        self.start() + self.size()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.start, self.layout) }
+    }
+}
+
+/// xorshift64, plenty for picking random sizes and alignments, and it keeps failing sequences reproducible from their seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.max(1)) // xorshift gets stuck at 0
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// random number in [low, high)
+    pub fn range(&mut self, low: usize, high: usize) -> usize {
+        low + (self.next_u64() % (high - low) as u64) as usize
+    }
+}
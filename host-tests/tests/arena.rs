@@ -0,0 +1,112 @@
+// Arena bookkeeping: bumping, handing back the most recent allocation, checkpoints and growing at the end.
+// The Allocator impl needs nightly, tests/arena_allocation.rs covers it in the kernel.
+
+use allocator_host_tests::allocator::arena::Arena;
+use std::alloc::Layout;
+
+fn arena(memory: &allocator_host_tests::Arena) -> Arena {
+    unsafe { Arena::new(memory.start(), memory.size()) }
+}
+
+#[test]
+fn bump_aligns_and_runs_out() {
+    let memory = allocator_host_tests::Arena::new(4096, 4096);
+    let arena = arena(&memory);
+
+    let first = arena.bump(Layout::from_size_align(3, 1).unwrap());
+    let second = arena.bump(Layout::from_size_align(8, 64).unwrap());
+    assert_eq!(first as usize, memory.start());
+    assert_eq!(second as usize, memory.start() + 64);
+    assert_eq!(arena.used(), 72);
+    assert_eq!(arena.remaining(), 4096 - 72);
+
+    assert!(arena.bump(Layout::from_size_align(4096, 1).unwrap()).is_null());
+    assert!(!arena.bump(Layout::from_size_align(4096 - 72, 1).unwrap()).is_null());
+    assert_eq!(arena.remaining(), 0);
+}
+
+#[test]
+fn empty_arena_fails_every_allocation() {
+    let arena = Arena::empty();
+    assert!(arena.bump(Layout::from_size_align(1, 1).unwrap()).is_null());
+    assert_eq!(arena.capacity(), 0);
+}
+
+#[test]
+fn only_most_recent_allocation_is_released_and_resized() {
+    let memory = allocator_host_tests::Arena::new(4096, 8);
+    let arena = arena(&memory);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let first = arena.bump(layout);
+    let second = arena.bump(layout);
+
+    unsafe { arena.release(first, 64) };
+    assert_eq!(arena.used(), 128);
+    assert!(!arena.resize_last(first, 64, 128));
+
+    assert!(arena.resize_last(second, 64, 256));
+    assert_eq!(arena.used(), 64 + 256);
+    assert!(!arena.resize_last(second, 256, 4096));
+    assert!(arena.resize_last(second, 256, 16));
+    assert_eq!(arena.used(), 64 + 16);
+
+    unsafe { arena.release(second, 16) };
+    assert_eq!(arena.used(), 64);
+}
+
+#[test]
+fn rewind_and_reset() {
+    let memory = allocator_host_tests::Arena::new(4096, 8);
+    let mut arena = arena(&memory);
+    let layout = Layout::from_size_align(100, 4).unwrap();
+    arena.bump(layout);
+
+    let checkpoint = arena.checkpoint();
+    arena.bump(layout);
+    arena.bump(layout);
+    arena.rewind(checkpoint);
+    assert_eq!(arena.used(), 100);
+
+    let used = arena.scope(|scratch| {
+        scratch.bump(layout);
+        scratch.used()
+    });
+    assert_eq!(used, 200);
+    assert_eq!(arena.used(), 100);
+
+    arena.reset();
+    assert_eq!(arena.used(), 0);
+    assert_eq!(arena.bump(layout) as usize, memory.start());
+}
+
+#[test]
+#[should_panic(expected = "isn't in use")]
+fn rewinding_past_a_reset_panics() {
+    let memory = allocator_host_tests::Arena::new(4096, 8);
+    let mut arena = arena(&memory);
+    arena.bump(Layout::from_size_align(100, 4).unwrap());
+    let checkpoint = arena.checkpoint();
+    arena.reset();
+    arena.rewind(checkpoint);
+}
+
+#[test]
+fn extend_at_the_end() {
+    let memory = allocator_host_tests::Arena::new(8192, 8);
+    let mut arena = unsafe { Arena::new(memory.start(), 4096) };
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    assert!(!arena.bump(layout).is_null());
+    assert!(arena.bump(layout).is_null());
+
+    unsafe { arena.extend(memory.start() + 4096, 4096) };
+    assert_eq!(arena.capacity(), 8192);
+    assert_eq!(arena.bump(layout) as usize, memory.start() + 4096);
+}
+
+#[test]
+#[should_panic(expected = "only be extended at its end")]
+fn extend_elsewhere_panics() {
+    let memory = allocator_host_tests::Arena::new(8192, 8);
+    let mut arena = unsafe { Arena::new(memory.start(), 4096) };
+    unsafe { arena.extend(memory.start() + 4097, 4095) };
+}
@@ -0,0 +1,204 @@
+// Random alloc/realloc/free sequences against every backend, checking that allocations are aligned,
+// stay inside the arena, never overlap, keep their contents through realloc, and that freeing
+// everything gives all the memory back.
+
+use allocator_host_tests::allocator::{
+    buddy::BuddyAllocator, bump::BumpAllocator, fixed_size_block::FixedSizeBlockAllocator,
+    linked_list::LinkedListAllocator, HeapStatistics, Locked,
+};
+use allocator_host_tests::{Arena, Rng};
+use std::alloc::{GlobalAlloc, Layout};
+use std::collections::BTreeMap;
+
+const ARENA_SIZE: usize = 1024 * 1024;
+const SEEDS: u64 = 20;
+const OPERATIONS: usize = 2000;
+
+struct Allocation {
+    layout: Layout,
+    fill: u8,
+}
+
+// allocations that are still live, by start address
+struct Live {
+    allocations: BTreeMap<usize, Allocation>,
+}
+
+impl Live {
+    fn new() -> Self {
+        Live { allocations: BTreeMap::new() }
+    }
+
+    fn insert(&mut self, ptr: *mut u8, layout: Layout, arena: &Arena, seed: u64) {
+        let start = ptr as usize;
+        let end = start + layout.size();
+        assert_eq!(start % layout.align(), 0, "seed {}: {:#x} isn't aligned for {:?}", seed, start, layout);
+        assert!(
+            start >= arena.start() && end <= arena.end(),
+            "seed {}: {:#x}..{:#x} is outside of the arena", seed, start, end
+        );
+        // closest allocations on both sides must end before this one starts, and start after it ends
+        if let Some((&before, allocation)) = self.allocations.range(..=start).next_back() {
+            assert!(before + allocation.layout.size() <= start, "seed {}: {:#x} overlaps {:#x}", seed, start, before);
+        }
+        if let Some((&after, _)) = self.allocations.range(start..).next() {
+            assert!(end <= after, "seed {}: {:#x} overlaps {:#x}", seed, start, after);
+        }
+
+        // fill it, so that a later overlapping allocation or a corrupted free list shows up when it's freed
+        let fill = (start / 8) as u8;
+        unsafe { ptr.write_bytes(fill, layout.size()) };
+        self.allocations.insert(start, Allocation { layout, fill });
+    }
+
+    fn remove(&mut self, start: usize, seed: u64) -> Layout {
+        let allocation = self.allocations.remove(&start).unwrap();
+        let bytes = unsafe { std::slice::from_raw_parts(start as *const u8, allocation.layout.size()) };
+        assert!(
+            bytes.iter().all(|&b| b == allocation.fill),
+            "seed {}: {:#x} was overwritten while it was allocated", seed, start
+        );
+        allocation.layout
+    }
+
+    // checks the first `size` bytes still hold the fill pattern of the allocation that moved to ptr
+    fn check_moved(&self, ptr: *mut u8, size: usize, fill: u8, seed: u64) {
+        let bytes = unsafe { std::slice::from_raw_parts(ptr, size) };
+        assert!(bytes.iter().all(|&b| b == fill), "seed {}: realloc to {:p} lost the contents", seed, ptr);
+    }
+
+    fn random(&self, rng: &mut Rng) -> Option<usize> {
+        if self.allocations.is_empty() {
+            return None;
+        }
+        self.allocations.keys().nth(rng.range(0, self.allocations.len())).copied()
+    }
+}
+
+fn random_layout(rng: &mut Rng, max_size: usize) -> Layout {
+    let size = rng.range(1, max_size + 1);
+    let align = 1 << rng.range(0, 7); // up to 64
+    Layout::from_size_align(size, align).unwrap()
+}
+
+// runs a random sequence, then frees everything that's left
+fn alloc_free_sequence<A: GlobalAlloc>(allocator: &A, arena: &Arena, seed: u64, max_size: usize) {
+    let mut rng = Rng::new(seed);
+    let mut live = Live::new();
+    for _ in 0..OPERATIONS {
+        // allocate a bit more often than free, so the heap fills up over time
+        if rng.range(0, 5) < 3 {
+            let layout = random_layout(&mut rng, max_size);
+            let ptr = unsafe { allocator.alloc(layout) };
+            if !ptr.is_null() {
+                live.insert(ptr, layout, arena, seed);
+            }
+        } else if rng.range(0, 2) == 0 {
+            if let Some(start) = live.random(&mut rng) {
+                let fill = live.allocations[&start].fill;
+                let layout = live.remove(start, seed);
+                let new_size = rng.range(1, max_size + 1);
+                let ptr = unsafe { allocator.realloc(start as *mut u8, layout, new_size) };
+                if ptr.is_null() {
+                    // the old allocation is untouched when realloc fails
+                    live.insert(start as *mut u8, layout, arena, seed);
+                } else {
+                    live.check_moved(ptr, layout.size().min(new_size), fill, seed);
+                    live.insert(ptr, Layout::from_size_align(new_size, layout.align()).unwrap(), arena, seed);
+                }
+            }
+        } else if let Some(start) = live.random(&mut rng) {
+            let layout = live.remove(start, seed);
+            unsafe { allocator.dealloc(start as *mut u8, layout) };
+        }
+    }
+    while let Some(start) = live.random(&mut rng) {
+        let layout = live.remove(start, seed);
+        unsafe { allocator.dealloc(start as *mut u8, layout) };
+    }
+}
+
+#[test]
+fn bump_allocator() {
+    for seed in 1..=SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 8);
+        let allocator = Locked::new(BumpAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+        alloc_free_sequence(&allocator, &arena, seed, 512);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.bytes_in_use, 0);
+        assert_eq!(stats.free_bytes, ARENA_SIZE, "seed {}: memory wasn't reclaimed", seed);
+    }
+}
+
+#[test]
+fn bump_allocator_reuses_most_recent_allocation() {
+    let arena = Arena::new(4096, 8);
+    let allocator = Locked::new(BumpAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    unsafe {
+        let first = allocator.alloc(layout);
+        let second = allocator.alloc(layout);
+        assert_eq!(first as usize, arena.start());
+        assert_eq!(second as usize, arena.start() + 64);
+
+        allocator.dealloc(second, layout);
+        assert_eq!(allocator.alloc(layout), second);
+        allocator.dealloc(first, layout); // not the most recent one, stays in use until everything is freed
+        assert_eq!(allocator.alloc(layout) as usize, arena.start() + 128);
+    }
+}
+
+#[test]
+fn linked_list_allocator() {
+    for seed in 1..=SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 8);
+        let allocator = Locked::new(LinkedListAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+        alloc_free_sequence(&allocator, &arena, seed, 4096);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.bytes_in_use, 0);
+        // all free regions have to merge back into one
+        assert_eq!(stats.largest_free_block, Some(ARENA_SIZE), "seed {}: memory wasn't reclaimed", seed);
+    }
+}
+
+#[test]
+fn fixed_size_block_allocator() {
+    for seed in 1..=SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 8);
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+        alloc_free_sequence(&allocator, &arena, seed, 4096);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.bytes_in_use, 0);
+        assert_eq!(stats.allocations, stats.frees);
+
+        // every slab has to be handed back, apart from the empty one each size keeps until shrink
+        allocator.lock().shrink();
+        let stats = allocator.lock().stats();
+        assert!(stats.free_blocks_per_size.unwrap().iter().all(|&count| count == 0));
+        assert_eq!(stats.largest_free_block, Some(ARENA_SIZE), "seed {}: memory wasn't reclaimed", seed);
+    }
+}
+
+#[test]
+fn buddy_allocator() {
+    for seed in 1..=SEEDS {
+        let arena = Arena::new(ARENA_SIZE, ARENA_SIZE);
+        let allocator = Locked::new(BuddyAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+        alloc_free_sequence(&allocator, &arena, seed, 4096);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.bytes_in_use, 0);
+        // every buddy has to merge back up into the single block the arena started as
+        assert_eq!(stats.free_bytes, ARENA_SIZE);
+        assert_eq!(stats.largest_free_block, Some(ARENA_SIZE), "seed {}: memory wasn't reclaimed", seed);
+    }
+}
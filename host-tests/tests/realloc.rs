@@ -0,0 +1,93 @@
+// realloc resizing allocations in place instead of allocating, copying and freeing
+
+use allocator_host_tests::allocator::{
+    fixed_size_block::FixedSizeBlockAllocator, linked_list::LinkedListAllocator, HeapStatistics, Locked,
+};
+use allocator_host_tests::Arena;
+use std::alloc::{GlobalAlloc, Layout};
+
+fn linked_list(arena: &Arena) -> Locked<LinkedListAllocator> {
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    allocator
+}
+
+#[test]
+fn linked_list_grows_into_following_free_region() {
+    let arena = Arena::new(4096, 8);
+    let allocator = linked_list(&arena);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(0xab, 64);
+        assert_eq!(allocator.realloc(ptr, layout, 1024), ptr);
+        assert!(std::slice::from_raw_parts(ptr, 64).iter().all(|&b| b == 0xab));
+
+        let stats = allocator.lock().stats();
+        assert_eq!((stats.reallocs_in_place, stats.reallocs_moved), (1, 0));
+        assert_eq!(stats.bytes_in_use, 1024);
+        assert_eq!(stats.free_bytes, 4096 - 1024);
+    }
+}
+
+#[test]
+fn linked_list_shrinks_in_place() {
+    let arena = Arena::new(4096, 8);
+    let allocator = linked_list(&arena);
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        let blocker = allocator.alloc(layout); // nothing free right behind the first allocation
+        assert_eq!(allocator.realloc(ptr, layout, 256), ptr);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.reallocs_in_place, 1);
+        assert_eq!(stats.free_bytes, 4096 - 256 - 1024);
+
+        // the freed tail can be handed out again
+        let small = Layout::from_size_align(768, 8).unwrap();
+        assert_eq!(allocator.alloc(small) as usize, ptr as usize + 256);
+        allocator.dealloc(blocker, layout);
+    }
+}
+
+#[test]
+fn linked_list_moves_when_next_region_is_taken() {
+    let arena = Arena::new(4096, 8);
+    let allocator = linked_list(&arena);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        let _blocker = allocator.alloc(layout);
+        ptr.write_bytes(0xab, 64);
+        let moved = allocator.realloc(ptr, layout, 256);
+        assert_ne!(moved, ptr);
+        assert!(std::slice::from_raw_parts(moved, 64).iter().all(|&b| b == 0xab));
+        let stats = allocator.lock().stats();
+        assert_eq!((stats.reallocs_in_place, stats.reallocs_moved), (0, 1));
+    }
+}
+
+#[test]
+fn fixed_size_block_stays_within_size_class() {
+    let arena = Arena::new(64 * 1024, 8);
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        // 40 and 64 bytes both fit a 64 byte block
+        assert_eq!(allocator.realloc(ptr, layout, 64), ptr);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let moved = allocator.realloc(ptr, layout, 65);
+        assert_ne!(moved, ptr);
+        // past the largest block size, the fallback allocator resizes in place
+        let layout = Layout::from_size_align(65, 8).unwrap();
+        let large = allocator.realloc(moved, layout, 4096);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        assert_eq!(allocator.realloc(large, layout, 8192), large);
+
+        let stats = allocator.lock().stats();
+        assert_eq!((stats.reallocs_in_place, stats.reallocs_moved), (2, 2));
+        assert_eq!(stats.bytes_in_use, 8192);
+    }
+}
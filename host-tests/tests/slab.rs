@@ -0,0 +1,128 @@
+// slab caches: objects come from page sized slabs, which go back once they're empty
+
+use allocator_host_tests::allocator::slab::{RawSlabCache, SlabCache};
+use allocator_host_tests::Arena;
+use std::collections::HashSet;
+
+#[test]
+fn objects_fill_a_slab_before_the_next_one() {
+    let mut cache = RawSlabCache::new(64, 64);
+    assert_eq!(cache.allocate(), None);
+    assert_eq!(cache.slab_layout().size(), 4096);
+
+    let arena = Arena::new(2 * 4096, 4096);
+    unsafe { cache.add_slab(arena.start() as *mut u8) };
+    let per_slab = cache.objects_per_slab();
+    assert_eq!(per_slab, 4096 / 64 - 1); // the first object slot holds the slab header
+
+    let mut objects = HashSet::new();
+    for _ in 0..per_slab {
+        let object = cache.allocate().unwrap() as usize;
+        assert_eq!(object % 64, 0);
+        assert!(object >= arena.start() && object + 64 <= arena.start() + 4096);
+        assert!(objects.insert(object));
+    }
+    assert_eq!(cache.allocate(), None);
+
+    unsafe { cache.add_slab((arena.start() + 4096) as *mut u8) };
+    let object = cache.allocate().unwrap() as usize;
+    assert!(object >= arena.start() + 4096);
+    assert_eq!((cache.slabs(), cache.objects_in_use()), (2, per_slab + 1));
+}
+
+#[test]
+fn empty_slabs_are_handed_back() {
+    let mut cache = RawSlabCache::new(256, 256);
+    let slab_size = cache.slab_layout().size();
+    let arena = Arena::new(3 * slab_size, slab_size);
+    let per_slab = cache.objects_per_slab();
+    let mut objects = Vec::new();
+    for slab in 0..3 {
+        unsafe { cache.add_slab((arena.start() + slab * slab_size) as *mut u8) };
+        for _ in 0..per_slab {
+            objects.push(cache.allocate().unwrap());
+        }
+    }
+
+    let mut released = Vec::new();
+    for object in objects {
+        if let Some(slab) = unsafe { cache.deallocate(object) } {
+            released.push(slab as usize);
+        }
+    }
+    // the first slab to run empty stays cached, the other two go back
+    assert_eq!(released.len(), 2);
+    assert_eq!(cache.slabs(), 1);
+    assert_eq!(cache.free_objects(), per_slab);
+
+    // the cached slab is used again before asking for a new one
+    let slab = cache.allocate().unwrap() as usize & !(slab_size - 1);
+    assert!(!released.contains(&slab));
+    assert_eq!(cache.slabs(), 1);
+}
+
+#[test]
+fn freeing_into_a_full_slab_makes_it_the_next_one_used() {
+    let mut cache = RawSlabCache::new(512, 512);
+    let slab_size = cache.slab_layout().size();
+    let arena = Arena::new(3 * slab_size, slab_size);
+    let per_slab = cache.objects_per_slab();
+    let mut objects = Vec::new();
+    for slab in 0..3 {
+        unsafe { cache.add_slab((arena.start() + slab * slab_size) as *mut u8) };
+        for _ in 0..per_slab {
+            objects.push(cache.allocate().unwrap());
+        }
+    }
+    assert_eq!(cache.allocate(), None);
+
+    // the middle slab sits between the other two on the full list
+    let middle = arena.start() + slab_size;
+    let object = objects[per_slab];
+    assert_eq!(object as usize & !(slab_size - 1), middle);
+    assert_eq!(unsafe { cache.deallocate(object) }, None);
+    assert_eq!(cache.allocate(), Some(object));
+    assert_eq!(cache.allocate(), None);
+
+    // the other two are still on the list and can be emptied
+    let mut released = 0;
+    for object in objects {
+        if unsafe { cache.deallocate(object) }.is_some() {
+            released += 1;
+        }
+    }
+    assert_eq!((released, cache.slabs(), cache.objects_in_use()), (2, 1, 0));
+}
+
+#[test]
+fn large_objects_get_multi_page_slabs() {
+    let cache = RawSlabCache::new(2048, 2048);
+    assert!(cache.objects_per_slab() >= 8);
+    assert_eq!(cache.slab_layout().size(), cache.slab_layout().align());
+    assert!(cache.slab_layout().size() > 4096);
+}
+
+#[derive(Debug, PartialEq)]
+struct Task {
+    id: u64,
+    stack_pointer: usize,
+    name: [u8; 16],
+}
+
+static TASKS: SlabCache<Task> = SlabCache::new();
+
+#[test]
+fn typed_cache_reuses_objects() {
+    let first = TASKS.alloc(Task { id: 1, stack_pointer: 0x1000, name: *b"init            " });
+    let address = &*first as *const Task as usize;
+    assert_eq!(first.id, 1);
+    assert_eq!(address % std::mem::align_of::<Task>(), 0);
+    drop(first);
+
+    let mut second = TASKS.alloc(Task { id: 2, stack_pointer: 0x2000, name: [0; 16] });
+    second.stack_pointer += 8;
+    assert_eq!(&*second as *const Task as usize, address);
+    assert_eq!(*second, Task { id: 2, stack_pointer: 0x2008, name: [0; 16] });
+    assert_eq!(TASKS.objects_in_use(), 1);
+    assert_eq!(TASKS.slabs(), 1);
+}
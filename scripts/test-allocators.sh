@@ -0,0 +1,23 @@
+#!/bin/sh
+# Runs the heap tests once per heap backend, so allocators can be compared without editing src/allocator.rs
+set -e
+
+cd "$(dirname "$0")/.."
+
+for backend in alloc-bump alloc-linked-list alloc-fixed-block alloc-buddy alloc-external; do
+    echo "=== $backend"
+    cargo test --no-default-features --features "$backend" --test heap_allocation
+    # the external heap doesn't call the reclaim callback
+    if [ "$backend" != alloc-external ]; then
+        cargo test --no-default-features --features "$backend" --test oom_reclaim
+    fi
+done
+
+# many_boxes_long_lived is expected to run the bump allocator out of memory
+cargo test --no-default-features --features alloc-bump --test bump_long_lived
+
+# the debug wrapper's tests need its feature, a plain cargo test skips them
+cargo test --features alloc-debug --test debug_double_free --test debug_heap_overflow
+
+# same for leak tracking
+cargo test --features alloc-tracking --test leak_tracking
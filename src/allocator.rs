@@ -1,10 +1,24 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use linked_list_allocator::LockedHeap;
 
+mod common;
+pub use common::{ExtendHeap, Locked};
+use common::{align_up, realloc_by_moving};
+
+pub mod arena;
 pub mod bump;
+#[cfg(feature = "alloc-debug")]
+pub mod debug;
+#[cfg(feature = "alloc-tracking")]
+pub mod tracking;
+pub mod buddy;
 pub mod linked_list;
 pub mod fixed_size_block;
+pub mod oom;
+pub mod slab;
+pub mod stats;
+
+pub use stats::{HeapStatistics, HeapStats};
 // happens to be a Zero Sized Type https://doc.rust-lang.org/nomicon/exotic-sizes.html#zero-sized-types-zsts
 pub struct Dummy;
 
@@ -19,33 +33,246 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
-// use bump::BumpAllocator;
-use linked_list::LinkedListAllocator;
-use fixed_size_block::FixedSizeBlockAllocator;
+// the backend is picked with the alloc-* cargo features, enabling more than one ends in a duplicate HeapAllocator error.
+// init_heap and heap_stats always talk to ALLOCATOR directly, it's only the #[global_allocator]
+// itself when no debugging layer is enabled on top of it
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-buddy",
+    feature = "alloc-external"
+)))]
+compile_error!("no heap backend selected, enable one of the alloc-* features");
+
+#[cfg(feature = "alloc-bump")]
+type HeapAllocator = Locked<bump::BumpAllocator>;
+#[cfg(feature = "alloc-bump")]
+#[cfg_attr(not(any(feature = "alloc-debug", feature = "alloc-tracking")), global_allocator)]
+static ALLOCATOR: HeapAllocator = Locked::new(bump::BumpAllocator::new());
+
+#[cfg(feature = "alloc-linked-list")]
+type HeapAllocator = Locked<linked_list::LinkedListAllocator>;
+#[cfg(feature = "alloc-linked-list")]
+#[cfg_attr(not(any(feature = "alloc-debug", feature = "alloc-tracking")), global_allocator)]
+static ALLOCATOR: HeapAllocator = Locked::new(linked_list::LinkedListAllocator::new());
+
+#[cfg(feature = "alloc-fixed-block")]
+type HeapAllocator = Locked<fixed_size_block::FixedSizeBlockAllocator>;
+#[cfg(feature = "alloc-fixed-block")]
+#[cfg_attr(not(any(feature = "alloc-debug", feature = "alloc-tracking")), global_allocator)]
+static ALLOCATOR: HeapAllocator = Locked::new(fixed_size_block::FixedSizeBlockAllocator::new());
+
+#[cfg(feature = "alloc-buddy")]
+type HeapAllocator = Locked<buddy::BuddyAllocator>;
+#[cfg(feature = "alloc-buddy")]
+#[cfg_attr(not(any(feature = "alloc-debug", feature = "alloc-tracking")), global_allocator)]
+static ALLOCATOR: HeapAllocator = Locked::new(buddy::BuddyAllocator::new());
+
+#[cfg(feature = "alloc-external")]
+type HeapAllocator = linked_list_allocator::LockedHeap;
+#[cfg(feature = "alloc-external")]
+#[cfg_attr(not(any(feature = "alloc-debug", feature = "alloc-tracking")), global_allocator)]
+static ALLOCATOR: HeapAllocator = linked_list_allocator::LockedHeap::empty();
+
+// the linked_list_allocator crate only knows its size and how much of it is used
+#[cfg(feature = "alloc-external")]
+impl HeapStatistics for linked_list_allocator::Heap {
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            heap_size: self.size(),
+            bytes_in_use: self.used(),
+            free_bytes: self.free(),
+            ..HeapStats::default()
+        }
+    }
+}
+
+// red zones, poisoning and double free checks around every allocation
+#[cfg(feature = "alloc-debug")]
+#[cfg_attr(not(feature = "alloc-tracking"), global_allocator)]
+static DEBUG_ALLOCATOR: debug::DebugAllocator<HeapAllocator> = debug::DebugAllocator::new(&ALLOCATOR);
+
+// records live allocations for leak checks in test_runner, always the outermost layer
+#[cfg(all(feature = "alloc-tracking", not(feature = "alloc-debug")))]
+#[global_allocator]
+static TRACKING_ALLOCATOR: tracking::TrackingAllocator<HeapAllocator> = tracking::TrackingAllocator::new(&ALLOCATOR);
+#[cfg(all(feature = "alloc-tracking", feature = "alloc-debug"))]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
-// static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static TRACKING_ALLOCATOR: tracking::TrackingAllocator<debug::DebugAllocator<HeapAllocator>> =
+    tracking::TrackingAllocator::new(&DEBUG_ALLOCATOR);
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 Kb
+pub const HEAP_START: usize = 0x_4444_4440_0000; // 2 Mb aligned, so that it can be mapped with huge pages
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 Kb, mapped up front by init_heap
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 Mb, default limit for growing the heap
+// grow at least this much at once, so that a burst of small allocations doesn't map one page at a time
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+// 4 KiB frames in a 2 Mb one, for memory::report
+const FRAMES_PER_HUGE_PAGE: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
 
+use crate::memory::huge_page::{self, HugeFrameAllocator};
+use crate::memory::report::{self, PageTableFrames, Purpose};
+use crate::memory::vma::{Backing, Vma};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
+// current end of the mapped heap and how far it's allowed to grow
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_START + HEAP_MAX_SIZE);
+// address of the allocator managing the kernel heap, other instances (e.g. over a test arena) never grow
+static HEAP_OWNER: AtomicUsize = AtomicUsize::new(0);
+// set by init_heap_with_huge_pages, the heap then also grows 2 Mb at a time
+static HEAP_HUGE_PAGES: AtomicBool = AtomicBool::new(false);
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
 
+    reserve_heap_range(Backing::Anonymous);
+    map_heap_pages(HEAP_START, HEAP_START + HEAP_SIZE, mapper, frame_allocator)?;
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::SeqCst);
+
+    let mut allocator = ALLOCATOR.lock();
+    unsafe {
+        allocator.init(HEAP_START, HEAP_SIZE);
+    }
+    HEAP_OWNER.store(&mut *allocator as *mut _ as usize, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Like `init_heap`, but maps the heap with 2 Mb pages, which takes a lot less TLB entries once the heap gets large.
+///
+/// The heap starts out with one 2 Mb page instead of `HEAP_SIZE`, and grows by whole 2 Mb pages.
+pub fn init_heap_with_huge_pages(
+    mapper: &mut impl Mapper<Size2MiB>,
+    frame_allocator: &mut impl HugeFrameAllocator,
+) -> Result<(), MapToError<Size2MiB>> {
+    let heap_size = Size2MiB::SIZE as usize;
+    let start = Page::containing_address(VirtAddr::new(HEAP_START as u64));
+    let flags = heap_flags();
+    reserve_heap_range(Backing::Anonymous);
+    huge_page::map_huge_pages(mapper, start, 1, flags, frame_allocator)?;
+    report::count_allocated(Purpose::Heap, FRAMES_PER_HUGE_PAGE);
+    HEAP_END.store(HEAP_START + heap_size, Ordering::SeqCst);
+    HEAP_HUGE_PAGES.store(true, Ordering::SeqCst);
+
+    let mut allocator = ALLOCATOR.lock();
+    unsafe {
+        allocator.init(HEAP_START, heap_size);
+    }
+    HEAP_OWNER.store(&mut *allocator as *mut _ as usize, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Hands the heap up to its maximum size to the allocator right away, without mapping anything:
+/// each page gets its frame from the page fault handler when it's first touched.
+///
+/// Has to be called after `memory::init_kernel_paging`, and with the IDT loaded (`crate::init`),
+/// since the allocator writes to the heap as soon as it's set up.
+pub fn init_heap_lazy() {
+    reserve_heap_range(Backing::Lazy);
+    let heap_end = HEAP_LIMIT.load(Ordering::SeqCst);
+    // nothing left to grow into
+    HEAP_END.store(heap_end, Ordering::SeqCst);
+
+    let mut allocator = ALLOCATOR.lock();
+    unsafe {
+        allocator.init(HEAP_START, heap_end - HEAP_START);
+    }
+    HEAP_OWNER.store(&mut *allocator as *mut _ as usize, Ordering::SeqCst);
+}
+
+// the whole range the heap may grow into is kept clear of other mappings
+fn reserve_heap_range(backing: Backing) {
+    crate::memory::vma::reserve_at(Vma {
+        start: VirtAddr::new(HEAP_START as u64),
+        size: HEAP_MAX_SIZE as u64,
+        purpose: "kernel heap",
+        flags: heap_flags(),
+        backing,
+        // only lazy heap pages are mapped by the page fault handler, map_heap_pages counts the others itself
+        counted_as: Some(Purpose::Heap),
+    })
+    .expect("heap range overlaps another mapping");
+}
+
+/// Sets how large the heap may grow, counting the initial `HEAP_SIZE`.
+///
+/// Doesn't unmap anything, so a limit below the current heap size just stops further growth.
+/// Can't be more than `HEAP_MAX_SIZE`, that's the virtual range reserved for the heap.
+pub fn set_max_heap_size(max_size: usize) {
+    HEAP_LIMIT.store(HEAP_START + max_size.min(HEAP_MAX_SIZE), Ordering::SeqCst);
+}
+
+/// Snapshot of the kernel heap usage, from whichever backend the alloc-* feature picked.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Live allocation counters right now, pass it to `check_leaks` later to find what was allocated and not freed in between.
+#[cfg(feature = "alloc-tracking")]
+pub fn allocation_mark() -> tracking::Mark {
+    TRACKING_ALLOCATOR.mark()
+}
+
+/// Prints every allocation made since `mark` that is still live over serial, and panics if there are any.
+#[cfg(feature = "alloc-tracking")]
+pub fn check_leaks(mark: tracking::Mark) {
+    let (mut count, mut bytes) = (0, 0);
+    TRACKING_ALLOCATOR.for_each_since(&mark, |allocation| {
+        if count == 0 {
+            crate::serial_println!();
+        }
+        count += 1;
+        bytes += allocation.size;
+        crate::serial_print!(
+            "  leaked {} bytes (align {}) at {:#x}",
+            allocation.size, allocation.align, allocation.ptr
+        );
+        if allocation.callers[0] != 0 {
+            crate::serial_print!(", callers {:x?}", allocation.callers);
+        }
+        crate::serial_println!();
+    });
+    // allocations that didn't fit in the table can't be listed, they only show up in the live count
+    let now = TRACKING_ALLOCATOR.mark();
+    if count == 0 && now.live_allocations > mark.live_allocations && TRACKING_ALLOCATOR.untracked() > 0 {
+        count = now.live_allocations - mark.live_allocations;
+        bytes = now.live_bytes.saturating_sub(mark.live_bytes);
+    }
+    if count > 0 {
+        panic!("{} allocations ({} bytes) leaked", count, bytes);
+    }
+}
+
+// heap pages hold data only, never code
+fn heap_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+/// Current size of the mapped heap in bytes.
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::SeqCst) - HEAP_START
+}
+
+// maps the [start, end) heap range, start and end are expected to be page aligned
+fn map_heap_pages(
+    start: usize,
+    end: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64; // subtract 1, because we want an inclusive range
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = VirtAddr::new(end as u64) - 1u64; // subtract 1, because we want an inclusive range
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -55,45 +282,83 @@ pub fn init_heap(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = heap_flags();
 
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        unsafe { mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator))?.flush() };
+        report::count_allocated(Purpose::Heap, 1);
     }
-
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
-    }
-
     Ok(())
 }
 
+/// Maps more pages at the end of the heap, at least enough for an allocation of the given layout,
+/// and hands them over to `allocator`.
+///
+/// Returns false if the heap has reached its maximum size, memory::init_kernel_paging wasn't called yet,
+/// or `allocator` isn't the one `init_heap` set up. Called by the allocators when they run out of memory, with their lock held.
+fn grow_heap<A: ExtendHeap>(allocator: &mut A, layout: &Layout) -> bool {
+    if allocator as *mut A as usize != HEAP_OWNER.load(Ordering::SeqCst) {
+        return false;
+    }
+    let heap_end = HEAP_END.load(Ordering::SeqCst);
+    let huge_pages = HEAP_HUGE_PAGES.load(Ordering::SeqCst);
+    let page_size = if huge_pages { Size2MiB::SIZE } else { Size4KiB::SIZE } as usize;
+    // only whole pages fit below the limit
+    let heap_limit = HEAP_LIMIT.load(Ordering::SeqCst) & !(page_size - 1);
+    // size + align covers alignment padding in the worst case
+    let wanted = match layout.size().checked_add(layout.align()) {
+        Some(wanted) => wanted.max(HEAP_GROWTH_STEP),
+        None => return false,
+    };
+    let new_end = match heap_end.checked_add(wanted) {
+        Some(end) => align_up(end, page_size).min(heap_limit),
+        None => return false,
+    };
+    if new_end <= heap_end {
+        return false;
+    }
 
-
-// a wrapper around spin::Mutex because Rust doesn't allow implementing traites for types defined in other crates
-pub struct Locked<A> {
-    inner: spin::Mutex<A>,
-}
-
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: spin::Mutex::new(inner),
+    // map page by page, so that running out of frames half way still leaves us with a usable (if smaller) extension
+    let mapped_end = crate::memory::with_kernel_paging(|mapper, frame_allocator| {
+        let mut end = heap_end;
+        while end < new_end {
+            let mapped = if huge_pages {
+                let page = Page::containing_address(VirtAddr::new(end as u64));
+                let flags = heap_flags();
+                let mapped = huge_page::map_huge_pages(mapper, page, 1, flags, frame_allocator).is_ok();
+                if mapped {
+                    report::count_allocated(Purpose::Heap, FRAMES_PER_HUGE_PAGE);
+                }
+                mapped
+            } else {
+                map_heap_pages(end, end + page_size, mapper, frame_allocator).is_ok()
+            };
+            if !mapped {
+                break;
+            }
+            end += page_size;
         }
+        end
+    })
+    .unwrap_or(heap_end);
+    if mapped_end == heap_end {
+        return false;
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
-    }
+    HEAP_END.store(mapped_end, Ordering::SeqCst);
+    unsafe { allocator.extend(heap_end, mapped_end - heap_end) };
+    true
 }
 
-
-fn align_up(addr: usize, align: usize) -> usize {
-    // let reminder = addr % align;
-    // if reminder == 0 {
-    //     addr
-    // } else {
-    //     addr - reminder + align // if addr:97, align:10 => 97 - 7 + 10 => 100
-    // }
-    // apparently below does the same job as above, just faster
-    (addr + align - 1) & !(align - 1)
+/// Calls `alloc` until it returns something other than a null pointer, or the reclaim callback
+/// registered with `oom::set_oom_policy` has nothing more to free.
+///
+/// `alloc` must not hold the allocator lock when it returns, the callback usually frees memory.
+fn retry_after_reclaim(layout: &Layout, mut alloc: impl FnMut() -> *mut u8) -> *mut u8 {
+    let mut ptr = alloc();
+    let mut attempt = 0;
+    while ptr.is_null() && oom::reclaim(layout, attempt) {
+        attempt += 1;
+        ptr = alloc();
+    }
+    ptr
 }
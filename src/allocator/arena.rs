@@ -0,0 +1,191 @@
+use super::align_up;
+use alloc::alloc::Layout;
+use core::cell::Cell;
+use core::ptr;
+
+/// Bump allocator over a fixed range of memory, for scratch memory that is thrown away all at once
+/// (e.g. per request in a driver or parser).
+///
+/// Implements `Allocator`, so collections can live in it: `Vec::new_in(&arena)`, `Box::new_in(x, &arena)`.
+/// Freeing only hands memory back for the most recent allocation, everything else comes back with
+/// `reset`, or when rewinding to a `Checkpoint`. Both need `&mut self`, so nothing allocated
+/// from the arena can still be around by then.
+pub struct Arena {
+    start: usize,
+    end: usize,
+    next: Cell<usize>,
+}
+
+/// Position in an `Arena` to rewind to, see `Arena::checkpoint`.
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    next: usize,
+}
+
+impl Arena {
+    /// An arena without any memory, every allocation fails.
+    pub const fn empty() -> Self {
+        Arena {
+            start: 0,
+            end: 0,
+            next: Cell::new(0),
+        }
+    }
+
+    /// Creates an arena handing out the memory in `start..start + size`.
+    ///
+    /// # Safety
+    ///
+    /// The range must be mapped, unused, and stay that way for as long as the arena lives.
+    pub unsafe fn new(start: usize, size: usize) -> Self {
+        Arena {
+            start,
+            end: start + size,
+            next: Cell::new(start),
+        }
+    }
+
+    /// Adds the `size` bytes at `addr` to the arena, `addr` has to be its current end.
+    ///
+    /// # Safety
+    ///
+    /// Same as `new`, for the added range.
+    pub unsafe fn extend(&mut self, addr: usize, size: usize) {
+        assert_eq!(addr, self.end, "arena can only be extended at its end");
+        self.end += size;
+    }
+
+    /// Bumps past an allocation of the given layout, returns null pointer when it doesn't fit.
+    pub fn bump(&self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next.get(), layout.align());
+        // checked_add will prevent int overflow https://doc.rust-lang.org/std/primitive.usize.html#method.checked_add
+        match alloc_start.checked_add(layout.size()) {
+            Some(alloc_end) if alloc_end <= self.end => {
+                self.next.set(alloc_end);
+                alloc_start as *mut u8
+            }
+            _ => ptr::null_mut(), // OOM
+        }
+    }
+
+    /// Hands the memory of an allocation back, which only works if it's the most recent one.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `size` must describe an allocation from this arena that isn't used anymore.
+    pub unsafe fn release(&self, ptr: *mut u8, size: usize) {
+        if self.next.get() == ptr as usize + size {
+            self.next.set(ptr as usize);
+        }
+    }
+
+    /// Resizes the most recent allocation where it is, returns false for any other allocation or if there's no room.
+    pub fn resize_last(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+        let start = ptr as usize;
+        if self.next.get() != start + old_size {
+            return false;
+        }
+        match start.checked_add(new_size) {
+            Some(new_end) if new_end <= self.end => {
+                self.next.set(new_end);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Frees everything allocated from the arena.
+    pub fn reset(&mut self) {
+        self.next.set(self.start);
+    }
+
+    /// Remembers how much of the arena is used right now, `rewind` frees everything allocated after that.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint { next: self.next.get() }
+    }
+
+    /// Frees everything allocated since `checkpoint` was taken.
+    ///
+    /// Panics if the checkpoint doesn't belong to this arena, or everything after it was already freed.
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        assert!(
+            checkpoint.next >= self.start && checkpoint.next <= self.next.get(),
+            "rewinding to a checkpoint which isn't in use in this arena"
+        );
+        self.next.set(checkpoint.next);
+    }
+
+    /// Runs `f`, then frees everything it allocated from the arena.
+    pub fn scope<R>(&mut self, f: impl FnOnce(&Arena) -> R) -> R {
+        let checkpoint = self.checkpoint();
+        let result = f(self);
+        self.rewind(checkpoint);
+        result
+    }
+
+    /// Size of the whole arena in bytes.
+    pub fn capacity(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Bytes handed out so far, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.get() - self.start
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.end - self.next.get()
+    }
+}
+
+// the Allocator trait is still unstable, and host-tests/ builds this module with a stable compiler
+#[cfg(not(host_tests))]
+mod allocator_api {
+    use super::Arena;
+    use alloc::alloc::{AllocError, Allocator, Layout};
+    use core::ptr::{self, NonNull};
+
+    unsafe impl Allocator for Arena {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            let ptr = NonNull::new(self.bump(layout)).ok_or(AllocError)?;
+            Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.release(ptr.as_ptr(), layout.size())
+        }
+
+        unsafe fn grow(
+            &self,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            // a Vec being pushed to is usually the most recent allocation, and grows in place
+            if ptr.as_ptr() as usize % new_layout.align() == 0
+                && self.resize_last(ptr.as_ptr(), old_layout.size(), new_layout.size())
+            {
+                return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+            }
+            let new_ptr = self.allocate(new_layout)?;
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, old_layout.size());
+            Ok(new_ptr)
+        }
+
+        unsafe fn shrink(
+            &self,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            if ptr.as_ptr() as usize % new_layout.align() != 0 {
+                let new_ptr = self.allocate(new_layout)?;
+                ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, new_layout.size());
+                return Ok(new_ptr);
+            }
+            // the end of anything but the most recent allocation just stays unused until reset
+            self.resize_last(ptr.as_ptr(), old_layout.size(), new_layout.size());
+            Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use super::stats::{AllocCounters, HeapStatistics, HeapStats};
+use super::{align_up, ExtendHeap, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+
+/// Number of block sizes, the block size for order n is `min_block_size << n`.
+pub const ORDERS: usize = 32;
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Buddy allocator: every block has a power of two size and is aligned to it.
+///
+/// A block is split in two halves ("buddies") when a smaller one is needed, and on free it is
+/// merged with its buddy again whenever the buddy is free as well. Because blocks are aligned
+/// to their size, the buddy of a block is always found by flipping the block size bit of its address.
+///
+/// Free blocks are kept in one list per order, the list nodes are written into the free blocks
+/// themselves at `address + node_offset`. For a heap that offset is 0, for physical memory it's
+/// the offset at which the bootloader mapped it (see `memory::BuddyFrameAllocator`).
+pub struct BuddyAllocator {
+    free_lists: [Option<&'static mut ListNode>; ORDERS],
+    free_counts: [usize; ORDERS],
+    min_block_size: usize,
+    node_offset: usize,
+    counters: AllocCounters,
+}
+
+impl BuddyAllocator {
+    /// Creates an empty buddy allocator for a heap, with blocks of at least 16 bytes.
+    pub const fn new() -> Self {
+        Self::with_block_size(16, 0)
+    }
+
+    /// Creates an empty buddy allocator handing out blocks of at least `min_block_size` bytes,
+    /// whose free list nodes are kept at `node_offset` past the block address.
+    ///
+    /// `min_block_size` must be a power of two large enough to hold a pointer.
+    pub const fn with_block_size(min_block_size: usize, node_offset: usize) -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        BuddyAllocator {
+            free_lists: [EMPTY; ORDERS],
+            free_counts: [0; ORDERS],
+            min_block_size,
+            node_offset,
+            counters: AllocCounters::new(),
+        }
+    }
+
+    /// Initialises the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The given memory range must be unused. Also, this method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_region(heap_start, heap_size);
+        self.counters.heap_size += heap_size;
+    }
+
+    /// Hands the given memory range over to the allocator, split into the largest aligned blocks that fit.
+    ///
+    /// # Safety
+    ///
+    /// Same as `init`: the range must be unused and not already managed by this allocator.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        assert!(mem::size_of::<ListNode>() <= self.min_block_size);
+        let end = start + size;
+        let mut addr = align_up(start, self.min_block_size);
+        while addr + self.min_block_size <= end {
+            // largest block that both starts aligned at addr and fits before end
+            let mut order = 0;
+            while order + 1 < ORDERS {
+                let next_size = self.block_size(order + 1);
+                // block sizes are powers of two
+                if addr & (next_size - 1) != 0 || addr + next_size > end {
+                    break;
+                }
+                order += 1;
+            }
+            self.free_block(addr, order);
+            addr += self.block_size(order);
+        }
+    }
+
+    /// Size in bytes of the blocks of the given order.
+    pub fn block_size(&self, order: usize) -> usize {
+        self.min_block_size << order
+    }
+
+    /// Number of free blocks of the given order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_counts[order]
+    }
+
+    /// Total free memory in bytes.
+    pub fn free_bytes(&self) -> usize {
+        (0..ORDERS).map(|order| self.free_counts[order] * self.block_size(order)).sum()
+    }
+
+    /// Order of the smallest block that can hold the given layout, None if it's larger than the largest order.
+    pub fn order_for(&self, layout: &Layout) -> Option<usize> {
+        let required_block_size = layout.size().max(layout.align()).max(self.min_block_size);
+        (0..ORDERS).find(|&order| self.block_size(order) >= required_block_size)
+    }
+
+    /// Allocates a block of the given order, splitting a larger one if needed. Returns the block address.
+    pub fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        // smallest order at or above the requested one that has a free block
+        let available = (order..ORDERS).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(available);
+
+        // split down to the requested order, the upper half goes back on the free list each time
+        for split_order in (order..available).rev() {
+            unsafe { self.push(addr + self.block_size(split_order), split_order) };
+        }
+        Some(addr)
+    }
+
+    /// Frees a block previously returned by `allocate_block` with the same order,
+    /// merging it with its buddy as long as the buddy is free too.
+    ///
+    /// Finding out whether the buddy is free walks the free list of its order, so each merge
+    /// takes time linear in the number of free blocks of that order.
+    ///
+    /// # Safety
+    ///
+    /// The block must not be used anymore, and must not be freed twice.
+    pub unsafe fn free_block(&mut self, addr: usize, order: usize) {
+        let mut addr = addr;
+        let mut order = order;
+        while order + 1 < ORDERS {
+            let buddy = addr ^ self.block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            // merged block starts at the lower of the two buddies
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        let node_ptr = (addr + self.node_offset) as *mut ListNode;
+        node_ptr.write(ListNode {
+            next: self.free_lists[order].take(),
+        });
+        self.free_lists[order] = Some(&mut *node_ptr);
+        self.free_counts[order] += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> usize {
+        let node = self.free_lists[order].take().expect("popping from empty free list");
+        self.free_lists[order] = node.next.take();
+        self.free_counts[order] -= 1;
+        node as *mut ListNode as usize - self.node_offset
+    }
+
+    // removes the block at addr from the free list of the given order, returns false if it isn't there
+    fn remove(&mut self, addr: usize, order: usize) -> bool {
+        let node_addr = addr + self.node_offset;
+        let mut current = &mut self.free_lists[order];
+        while matches!(current, Some(node) if &**node as *const ListNode as usize != node_addr) {
+            current = &mut current.as_mut().unwrap().next;
+        }
+        match current.take() {
+            Some(node) => {
+                *current = node.next.take(); // from A -> B -> C to A -> C
+                self.free_counts[order] -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtendHeap for BuddyAllocator {
+    unsafe fn extend(&mut self, addr: usize, size: usize) {
+        // blocks at the old heap end merge with their free buddies in the new range through free_block
+        self.add_region(addr, size);
+        self.counters.heap_size += size;
+    }
+}
+
+impl HeapStatistics for BuddyAllocator {
+    fn stats(&self) -> HeapStats {
+        let largest_order = (0..ORDERS).rev().find(|&order| self.free_counts[order] > 0);
+        HeapStats {
+            free_bytes: self.free_bytes(),
+            largest_free_block: Some(largest_order.map_or(0, |order| self.block_size(order))),
+            ..self.counters.stats()
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        super::retry_after_reclaim(&layout, || {
+            let mut allocator = self.lock();
+            let order = match allocator.order_for(&layout) {
+                Some(order) => order,
+                None => return ptr::null_mut(),
+            };
+            let mut block = allocator.allocate_block(order);
+            // out of memory, map more pages at the end of the heap and try again
+            while block.is_none() && super::grow_heap(&mut *allocator, &layout) {
+                block = allocator.allocate_block(order);
+            }
+            match block {
+                Some(addr) => {
+                    allocator.counters.record_alloc(layout.size());
+                    addr as *mut u8
+                }
+                None => ptr::null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        let order = allocator.order_for(&layout).expect("freeing a block larger than any order");
+        allocator.free_block(ptr as usize, order);
+        allocator.counters.record_free(layout.size());
+    }
+}
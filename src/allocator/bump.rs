@@ -1,68 +1,95 @@
-use super::{align_up, Locked};
+use super::arena::Arena;
+use super::stats::{AllocCounters, HeapStatistics, HeapStats};
+use super::{ExtendHeap, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr;
 
+/// The whole heap as one `Arena`, which is only reset once every allocation is freed.
 pub struct BumpAllocator {
-    heap_start: usize,
-    heap_end: usize,
-    next: usize,
+    arena: Arena,
     allocations: usize,
+    counters: AllocCounters,
 }
 
 impl BumpAllocator {
     // Creates a new empty bump allocator
     pub const fn new() -> Self {
         BumpAllocator {
-            heap_start: 0,
-            heap_end: 0,
-            next: 0,
+            arena: Arena::empty(),
             allocations: 0,
+            counters: AllocCounters::new(),
         }
     }
 
     /// initializes the allocator with given heap (start, size) bounds
     ///
-    /// This method is unsafe because the caller must ensure that the given
-    /// memory range is unused. Also, this method must be called only once.
+    /// # Safety
+    ///
+    /// The given memory range must be unused. Also, this method must be called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.heap_start = heap_start;
-        self.heap_end = heap_start + heap_size;
-        self.next = heap_start;
+        self.arena = Arena::new(heap_start, heap_size);
+        self.counters.heap_size = heap_size;
     }
-}
 
-unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    /// Bumps past an allocation of the given layout, returns null pointer when it doesn't fit in the heap.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = self.arena.bump(layout);
+        if !ptr.is_null() {
+            self.allocations += 1;
+            self.counters.record_alloc(layout.size());
+        }
+        ptr
+    }
+}
 
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut balloc = self.lock();
-        let alloc_start = align_up(balloc.next, layout.align());
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        // checked_add will prevent int overflow https://doc.rust-lang.org/std/primitive.usize.html#method.checked_add
-        let end_addr_maybe = alloc_start.checked_add(layout.size());
-        let alloc_end = match end_addr_maybe {
-            Some(end) => end,
-            None => return ptr::null_mut(),
-        };
+impl ExtendHeap for BumpAllocator {
+    unsafe fn extend(&mut self, addr: usize, size: usize) {
+        self.arena.extend(addr, size);
+        self.counters.heap_size += size;
+    }
+}
 
-        if alloc_end > balloc.heap_end {
-            ptr::null_mut() // OOM exception
-        } else {
-            balloc.next = alloc_end;
-            balloc.allocations += 1;
-            balloc.next as *mut u8
+impl HeapStatistics for BumpAllocator {
+    fn stats(&self) -> HeapStats {
+        // only the space after next can be handed out, freed memory in front of it comes back once everything is freed
+        let free = self.arena.remaining();
+        HeapStats {
+            free_bytes: free,
+            largest_free_block: Some(free),
+            ..self.counters.stats()
         }
     }
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        super::retry_after_reclaim(&layout, || {
+            let mut balloc = self.lock();
+            let mut ptr = balloc.allocate(layout);
+            // out of memory, map more pages at the end of the heap and try again
+            while ptr.is_null() && super::grow_heap(&mut *balloc, &layout) {
+                ptr = balloc.allocate(layout);
+            }
+            ptr
+        })
+    }
 
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut balloc = self.lock();
+        balloc.counters.record_free(layout.size());
         balloc.allocations -= 1;
-        if balloc.next ==  (ptr as usize) + layout.size() {
-            balloc.next -= (ptr as usize) - layout.size();
-        }
+        // freeing the most recent allocation hands its memory straight back
+        balloc.arena.release(ptr, layout.size());
 
         if balloc.allocations == 0 {
-            balloc.next = balloc.heap_start;
+            balloc.arena.reset();
         };
     }
 }
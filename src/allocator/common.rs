@@ -0,0 +1,77 @@
+// pieces every backend builds on, kept free of anything kernel specific so that the
+// allocator modules also build for the host (see host-tests/)
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+// a wrapper around spin::Mutex because Rust doesn't allow implementing traites for types defined in other crates
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+
+pub(super) fn align_up(addr: usize, align: usize) -> usize {
+    // let reminder = addr % align;
+    // if reminder == 0 {
+    //     addr
+    // } else {
+    //     addr - reminder + align // if addr:97, align:10 => 97 - 7 + 10 => 100
+    // }
+    // apparently below does the same job as above, just faster
+    (addr + align - 1) & !(align - 1)
+}
+
+// what the default GlobalAlloc::realloc does, for allocators whose realloc couldn't resize in place:
+// allocate a new block, copy everything over and free the old one
+pub(super) unsafe fn realloc_by_moving<A: GlobalAlloc>(
+    allocator: &A,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = allocator.alloc(new_layout);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        allocator.dealloc(ptr, layout);
+    }
+    new_ptr
+}
+
+/// Heap allocators which can take over memory mapped right after the current end of their heap.
+pub trait ExtendHeap {
+    /// Adds `size` bytes starting at `addr`, the previous end of the heap, to the memory this allocator hands out.
+    ///
+    /// # Safety
+    ///
+    /// The range must be mapped, unused, and start exactly where the heap ends now.
+    unsafe fn extend(&mut self, addr: usize, size: usize);
+}
+
+// unit tests only build for the host, see host-tests/
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::align_up;
+
+    #[test]
+    fn align_up_rounds_to_next_multiple() {
+        assert_eq!(align_up(0, 8), 0);
+        assert_eq!(align_up(1, 8), 8);
+        assert_eq!(align_up(8, 8), 8);
+        assert_eq!(align_up(97, 16), 112);
+        assert_eq!(align_up(0x1001, 0x1000), 0x2000);
+        assert_eq!(align_up(5, 1), 5);
+    }
+}
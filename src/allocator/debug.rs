@@ -0,0 +1,173 @@
+use super::{align_up, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, slice};
+
+// every allocation handed out looks like this in memory:
+//
+//   | reserved | padding | Header | front red zone | user data ... | back red zone |
+//   ^ block returned by the wrapped allocator       ^ pointer handed to the caller
+//
+// `reserved` keeps the header clear of the free list node the wrapped allocator writes at the
+// start of a freed block, so the header survives long enough to spot a double free.
+// `padding` is whatever it takes to align the user data.
+const RESERVED: usize = 16;
+const RED_ZONE: usize = 16;
+
+// byte patterns, the same ones MSVC's debug heap uses
+const RED_ZONE_BYTE: u8 = 0xfd;
+const FRESH_BYTE: u8 = 0xcd; // newly allocated, uninitialized memory
+const POISON_BYTE: u8 = 0xdd; // freed memory
+
+const LIVE_MAGIC: u64 = 0x_a110_ca7e_d0b1_0c4e;
+const FREED_MAGIC: u64 = 0x_dead_f4ee_d0b1_0c4e;
+
+// freed blocks are held back from the wrapped allocator for a while, so that a double free
+// or a write through a dangling pointer is still caught before the memory is reused
+const QUARANTINE_SIZE: usize = 32;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+    user_ptr: usize,
+}
+
+struct Quarantine {
+    blocks: [Option<(*mut u8, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+// the raw pointers only ever point into the heap, which isn't tied to any thread
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+    /// Puts a freed block in quarantine, returns the oldest one if the quarantine was full.
+    fn push(&mut self, ptr: *mut u8, layout: Layout) -> Option<(*mut u8, Layout)> {
+        let evicted = self.blocks[self.next].replace((ptr, layout));
+        self.next = (self.next + 1) % QUARANTINE_SIZE;
+        evicted
+    }
+}
+
+/// Wraps another allocator with red zones, poisoning and double free detection.
+///
+/// Enabled with the `alloc-debug` feature, it then sits between the `#[global_allocator]` and
+/// the selected backend. Any failed check panics with the address and layout of the allocation:
+/// - red zones in front of and behind the allocation are checked on `dealloc`
+/// - freed memory is filled with 0xdd and checked again when it leaves the quarantine
+/// - freeing a pointer that was never allocated, or freeing it twice, is caught by the header magic
+///   (a double free is only guaranteed to be caught while the block is still in quarantine)
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+    quarantine: Locked<Quarantine>,
+}
+
+impl<A: 'static> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: Locked::new(Quarantine {
+                blocks: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+        }
+    }
+}
+
+// distance from the start of the wrapped block to the user data
+fn user_offset(layout: &Layout) -> usize {
+    align_up(RESERVED + mem::size_of::<Header>() + RED_ZONE, layout.align())
+}
+
+// layout of the block requested from the wrapped allocator
+fn inner_layout(layout: &Layout) -> Option<Layout> {
+    let size = user_offset(layout)
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align().max(mem::align_of::<Header>())).ok()
+}
+
+unsafe fn header(user_ptr: *mut u8) -> *mut Header {
+    user_ptr.sub(RED_ZONE + mem::size_of::<Header>()) as *mut Header
+}
+
+unsafe fn front_red_zone<'a>(user_ptr: *mut u8) -> &'a mut [u8] {
+    slice::from_raw_parts_mut(user_ptr.sub(RED_ZONE), RED_ZONE)
+}
+
+unsafe fn back_red_zone<'a>(user_ptr: *mut u8, layout: &Layout) -> &'a mut [u8] {
+    slice::from_raw_parts_mut(user_ptr.add(layout.size()), RED_ZONE)
+}
+
+impl<A: GlobalAlloc + 'static> DebugAllocator<A> {
+    // checks header and red zones of a block that is being freed
+    unsafe fn check_live(&self, ptr: *mut u8, layout: &Layout) {
+        let header = &*header(ptr);
+        match header.magic {
+            LIVE_MAGIC if header.user_ptr == ptr as usize => {}
+            FREED_MAGIC if header.user_ptr == ptr as usize => {
+                panic!("double free of {:p} with {:?}", ptr, layout)
+            }
+            _ => panic!("freeing {:p} with {:?}, which was never allocated", ptr, layout),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "freeing {:p} with {:?}, but it was allocated with size {} align {}",
+                ptr, layout, header.size, header.align
+            );
+        }
+        if front_red_zone(ptr).iter().any(|&b| b != RED_ZONE_BYTE) {
+            panic!("heap corruption: write in front of {:p} with {:?}", ptr, layout);
+        }
+        if back_red_zone(ptr, layout).iter().any(|&b| b != RED_ZONE_BYTE) {
+            panic!("heap corruption: write past the end of {:p} with {:?}", ptr, layout);
+        }
+    }
+
+    // hands a block leaving quarantine back to the wrapped allocator, after checking nobody wrote to it since it was freed
+    unsafe fn release(&self, ptr: *mut u8, layout: Layout) {
+        if slice::from_raw_parts(ptr, layout.size()).iter().any(|&b| b != POISON_BYTE) {
+            panic!("heap corruption: write to {:p} with {:?} after it was freed", ptr, layout);
+        }
+        let inner_layout = inner_layout(&layout).unwrap();
+        self.inner.dealloc(ptr.sub(user_offset(&layout)), inner_layout);
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for DebugAllocator<A> {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let inner_layout = match inner_layout(&layout) {
+            Some(inner_layout) => inner_layout,
+            None => return ptr::null_mut(),
+        };
+        let block = self.inner.alloc(inner_layout);
+        if block.is_null() {
+            return block;
+        }
+
+        let ptr = block.add(user_offset(&layout));
+        header(ptr).write(Header {
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+            align: layout.align(),
+            user_ptr: ptr as usize,
+        });
+        front_red_zone(ptr).iter_mut().for_each(|b| *b = RED_ZONE_BYTE);
+        back_red_zone(ptr, &layout).iter_mut().for_each(|b| *b = RED_ZONE_BYTE);
+        ptr.write_bytes(FRESH_BYTE, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.check_live(ptr, &layout);
+        (*header(ptr)).magic = FREED_MAGIC;
+        ptr.write_bytes(POISON_BYTE, layout.size());
+
+        let evicted = self.quarantine.lock().push(ptr, layout);
+        if let Some((old_ptr, old_layout)) = evicted {
+            self.release(old_ptr, old_layout);
+        }
+    }
+}
@@ -1,10 +1,7 @@
-//use super::linked_list::LinkedListAllocator; // for when Free block merging is ready
+use super::linked_list::LinkedListAllocator;
+use super::slab::RawSlabCache;
+use super::stats::{AllocCounters, HeapStatistics, HeapStats};
 use alloc::alloc::Layout;
-use core::{mem, ptr, ptr::NonNull};
-
-struct ListNode {
-    next : Option<&'static mut ListNode>,
-}
 
 
 // block sizes to use
@@ -12,38 +9,115 @@ struct ListNode {
 // because block sizes are powers of two they can also be used as block alignments (which have to be powers of two)
 // this simplifies things
 // also, don't define any block sizes smaller than 8 because each block must be capable of storing a 64-bit pointer to the next block when freed!
-const BLOCK_SIZES:  &[usize] = &[8, 16,  32, 64, 128, 256, 512, 1024, 2048];
-const MAX_BLOCKS_PER_SIZE: usize = 128;
+pub const BLOCK_SIZES:  &[usize] = &[8, 16,  32, 64, 128, 256, 512, 1024, 2048];
 
+/// Hands out small allocations from one slab cache per block size, larger ones from a linked list allocator.
+///
+/// The slabs themselves are whole pages taken from the linked list allocator, and given back
+/// to it once all of their blocks are freed (see `slab::RawSlabCache`).
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    list_sizes: [usize; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap,
-    //fallback_allocator: LinkedListAllocator, // for when Free block merging is ready
+    caches: [RawSlabCache; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+    counters: AllocCounters,
 }
 
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
+        // block sizes are powers of two, so they're also the block alignment
+        const fn cache(index: usize) -> RawSlabCache {
+            RawSlabCache::new(BLOCK_SIZES[index], BLOCK_SIZES[index])
+        }
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()], // list of Nones for heads ;)
-            list_sizes: [0; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
+            // one per BLOCK_SIZES entry
+            caches: [cache(0), cache(1), cache(2), cache(3), cache(4), cache(5), cache(6), cache(7), cache(8)],
+            fallback_allocator: LinkedListAllocator::new(),
+            counters: AllocCounters::new(),
         }
     }
 
 
+    /// # Safety
+    ///
+    /// The given memory range must be unused. Also, this method must be called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
+        self.counters.heap_size += heap_size;
+    }
+
+    /// Gives the empty slab each block size keeps cached back to the fallback allocator.
+    pub fn shrink(&mut self) {
+        for cache in self.caches.iter_mut() {
+            if let Some(slab) = cache.release_empty_slab() {
+                unsafe { self.fallback_allocator.deallocate(slab, cache.slab_layout()) };
+            }
+        }
     }
 
     // allocate using fallback_allocator in case size too large for any of our fixed size blocks
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        let mut ptr = unsafe { self.fallback_allocator.allocate(layout) };
+        // out of memory, map more pages at the end of the heap and try again
+        while ptr.is_null() && super::grow_heap(self, &layout) {
+            ptr = unsafe { self.fallback_allocator.allocate(layout) };
         }
+        ptr
+    }
+
+    // takes a block from the slab cache for BLOCK_SIZES[index], with a new slab from the fallback allocator if all slabs are full
+    fn slab_alloc(&mut self, index: usize) -> *mut u8 {
+        if let Some(block) = self.caches[index].allocate() {
+            return block;
+        }
+        let slab = self.fallback_alloc(self.caches[index].slab_layout());
+        if slab.is_null() {
+            return slab;
+        }
+        unsafe { self.caches[index].add_slab(slab) };
+        self.caches[index].allocate().unwrap()
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtendHeap for FixedSizeBlockAllocator {
+    unsafe fn extend(&mut self, addr: usize, size: usize) {
+        self.fallback_allocator.extend(addr, size);
+        self.counters.heap_size += size;
+    }
+}
+
+impl HeapStatistics for FixedSizeBlockAllocator {
+    fn stats(&self) -> HeapStats {
+        let fallback = self.fallback_allocator.stats();
+        let mut free_blocks = [0; BLOCK_SIZES.len()];
+        for (count, cache) in free_blocks.iter_mut().zip(self.caches.iter()) {
+            *count = cache.free_objects();
+        }
+        let cached_bytes: usize = BLOCK_SIZES.iter().zip(free_blocks.iter()).map(|(size, count)| size * count).sum();
+        let largest_cached_block = BLOCK_SIZES
+            .iter()
+            .zip(free_blocks.iter())
+            .filter(|&(_, &count)| count > 0)
+            .map(|(&size, _)| size)
+            .max()
+            .unwrap_or(0);
+
+        HeapStats {
+            free_bytes: fallback.free_bytes + cached_bytes,
+            largest_free_block: fallback.largest_free_block.map(|largest| largest.max(largest_cached_block)),
+            free_blocks_per_size: Some(free_blocks),
+            ..self.counters.stats()
+        }
+    }
+
+    // free blocks in the slabs show up in free_blocks_per_size, this is the fallback heap the slabs come from
+    fn for_each_free_region(&self, f: &mut dyn FnMut(usize, usize)) -> bool {
+        self.fallback_allocator.for_each_free_region(f)
     }
 }
 
@@ -55,71 +129,80 @@ fn list_index(layout: &Layout) -> Option<usize> {
 
 
 
-use super::Locked;
+use super::{ExtendHeap, Locked};
 use alloc::alloc::GlobalAlloc;
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(head_index) => {
-                match allocator.list_heads[head_index].take() {
-                    Some(free_block) => {
-                        allocator.list_heads[head_index] = free_block.next.take(); // "pointer magic", popping current free block from top of the list and pointing head to next element
-                        free_block as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // there is no block in the list for this block size, we need to allocate new list
-                        let block_size = BLOCK_SIZES[head_index];
-                        // below only works if all sizes are the power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        // once
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            },
-            None => allocator.fallback_alloc(layout),
-        }
+        super::retry_after_reclaim(&layout, || {
+            let mut allocator = self.lock();
+            let ptr = match list_index(&layout) {
+                Some(index) => allocator.slab_alloc(index),
+                None => allocator.fallback_alloc(layout),
+            };
+            if !ptr.is_null() {
+                allocator.counters.record_alloc(layout.size());
+            }
+            ptr
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.counters.record_free(layout.size());
         match list_index(&layout) {
-            Some(head_index) => {
-                let list_size = allocator.list_sizes[head_index];
-                // if list_size is still under MAX_BLOCK_PER_SIZE
-                if list_size < MAX_BLOCKS_PER_SIZE  {
-                    let new_node = ListNode {
-                        // pointer magic, we're making a new node and point it at current head.
-                        // Essentially push new_node to head of the list
-                        next: allocator.list_heads[head_index].take(),
-                    };
-                    //verify that block has the right size and alignment
-                    assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[head_index]);
-                    // this is where we need sizes to be power of 2 for alignment which has to be power of 2
-                    assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[head_index]);
-                    let new_node_ptr = ptr as *mut ListNode;
-                    new_node_ptr.write(new_node);
-                    // this is actually where list_head is populated.
-                    // 1. It starts empty,
-                    // 2. initial calls to alloc for given size use fallback_allocator
-                    // 3. when blocks from fallback_allocator get dealloc'ed they get added to list_heads by the line below
-                    allocator.list_heads[head_index] = Some(&mut *new_node_ptr); // write new node as head of this list_heads
-                    // add list_size for head_index
-                    allocator.list_sizes[head_index] += 1;
-                } else {
-                    // otherwise stop adding to list and deallocate this block
-                    let ptr = NonNull::new(ptr).unwrap();
-                    allocator.fallback_allocator.deallocate(ptr, layout);
+            Some(index) => {
+                // the slab is handed back once all of its blocks are free
+                if let Some(slab) = allocator.caches[index].deallocate(ptr) {
+                    let slab_layout = allocator.caches[index].slab_layout();
+                    allocator.fallback_allocator.deallocate(slab, slab_layout);
                 }
-            },
+            }
             None => {
-                let ptr = NonNull::new(ptr).unwrap(); // need to wrap the pointer in NonNull, it's what linked_list_allocator lib requires
                 allocator.fallback_allocator.deallocate(ptr, layout);
             }
         }
     }
 
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        {
+            let mut allocator = self.lock();
+            let in_place = match (list_index(&layout), list_index(&new_layout)) {
+                // the block has room for anything up to its block size already
+                (Some(old_index), Some(new_index)) => old_index == new_index,
+                (None, None) => allocator.fallback_allocator.resize_in_place(ptr, layout, new_size),
+                // moving between a block list and the fallback allocator
+                _ => false,
+            };
+            if in_place {
+                allocator.counters.record_realloc_in_place(layout.size(), new_size);
+                return ptr;
+            }
+        }
+        let new_ptr = super::realloc_by_moving(self, ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.lock().counters.record_realloc_moved();
+        }
+        new_ptr
+    }
+}
+
+// unit tests only build for the host, see host-tests/
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::{list_index, BLOCK_SIZES};
+    use alloc::alloc::Layout;
+
+    #[test]
+    fn list_index_picks_smallest_block_fitting_size_and_align() {
+        let index = |size, align| list_index(&Layout::from_size_align(size, align).unwrap());
+        assert_eq!(index(1, 1), Some(0));
+        assert_eq!(index(8, 8), Some(0));
+        assert_eq!(index(9, 1), Some(1));
+        assert_eq!(index(5, 64), Some(3)); // align decides, blocks are aligned to their size
+        assert_eq!(index(2048, 8), Some(BLOCK_SIZES.len() - 1));
+        assert_eq!(index(2049, 8), None); // goes to the fallback allocator
+    }
 }
@@ -1,4 +1,5 @@
-use super::{align_up, Locked};
+use super::stats::{AllocCounters, HeapStatistics, HeapStats};
+use super::{align_up, ExtendHeap, Locked};
 use core::{mem, ptr};
 use alloc::alloc::{GlobalAlloc, Layout};
 
@@ -29,34 +30,63 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    counters: AllocCounters,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self { // Self here is aliast for LinkedListAllocator
         Self {
-            head: ListNode::new(0)
+            head: ListNode::new(0),
+            counters: AllocCounters::new(),
         }
     }
 
 
-    // Initialise the allocator with heap bounds
+    /// Initialise the allocator with heap bounds
+    ///
+    /// # Safety
+    ///
+    /// The given memory range must be unused. Also, this method must be called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.counters.heap_size += heap_size;
     }
 
+    /// Adds the given memory region to the free list.
+    ///
+    /// The list is kept sorted by start address, so the region is merged with
+    /// its neighbours whenever they are directly adjacent to it.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         //make sure that the free region is aligned and has enough space to contain ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        let mut new_head = ListNode::new(size);
-        new_head.next = self.head.next.take(); // take() clears current head.next to None, and sets new_head.next to 2nd Node
-        let new_head_ptr = addr as *mut ListNode; // addr as pointer to ListNode
-        new_head_ptr.write(new_head); // write new_head ListNode to memory under  addr
+        // walk to the last region that starts before addr, the new region goes right after it
+        let mut current = &mut self.head;
+        while matches!(&current.next, Some(next) if next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
 
-        self.head.next = Some(&mut *new_head_ptr); // head.next -> new_head
+        let mut size = size;
+        // merge with the following region if it starts exactly where the new one ends
+        if matches!(&current.next, Some(next) if next.start_addr() == addr + size) {
+            let next = current.next.take().unwrap();
+            size += next.size;
+            current.next = next.next.take(); // from A -> B -> C to A -> C, B is swallowed by the new region
+        }
 
-        //todo!();
+        // head is a dummy node with size 0, real regions are never empty
+        if current.size > 0 && current.end_addr() == addr {
+            // previous region ends exactly where the new one starts, just grow it
+            current.size += size;
+        } else {
+            let mut new_node = ListNode::new(size);
+            new_node.next = current.next.take(); // take() clears current.next to None, and sets new_node.next to the following Node
+            let new_node_ptr = addr as *mut ListNode; // addr as pointer to ListNode
+            new_node_ptr.write(new_node); // write new_node ListNode to memory under addr
+
+            current.next = Some(&mut *new_node_ptr); // current -> new_node -> following
+        }
     }
 
     //Looks for a free region with a given size and alignment and removes it from the list
@@ -66,7 +96,7 @@ impl LinkedListAllocator {
 
         //iterate through the list, while there are tail Nodes
         while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
                 // this region will do for allocation, remove it from the list
                 let next_next = region.next.take(); // clear it's tail
                 let res = Some((current.next.take().unwrap(), alloc_start));
@@ -84,6 +114,11 @@ impl LinkedListAllocator {
 
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
         let alloc_start = align_up(region.start_addr(), align);
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            // same as with excess_size below, the gap in front of the allocation has to be able to go back onto the free list
+            return Err(());
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?; // force Option
         if alloc_end > region.end_addr() {
             // region is too small
@@ -100,6 +135,136 @@ impl LinkedListAllocator {
         Ok(alloc_start)
     }
 
+    /// Allocates a block for the given layout, returns null pointer if no free region is large enough.
+    ///
+    /// This is what `GlobalAlloc::alloc` does once it holds the lock, it's exposed so that
+    /// other allocators (like `FixedSizeBlockAllocator`) can use this one as their fallback.
+    ///
+    /// # Safety
+    ///
+    /// The allocator must have been initialized with `init`, and its memory must still be valid.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        // layout adjustments
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            let alloc_end = alloc_start.checked_add(size).expect("allow overflow");
+            // region is no longer on the list, give back whatever is left on both sides of the allocation
+            if alloc_start > region_start {
+                self.add_free_region(region_start, alloc_start - region_start);
+            }
+            let excess_size = region_end - alloc_end;
+            if excess_size > 0 {
+                self.add_free_region(alloc_end, excess_size);
+            }
+            self.counters.record_alloc(layout.size());
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Puts a block previously returned by `allocate` back on the free list,
+    /// merging it with any adjacent free regions.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate` (or `resize_in_place`) on this allocator with the same layout,
+    /// and not be freed already.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        //perform layout adjustments
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+        self.counters.record_free(layout.size());
+    }
+
+    /// Resizes a block previously returned by `allocate` without moving it, returns false if that isn't possible.
+    ///
+    /// Shrinking gives the end of the block back to the free list, growing takes the missing bytes
+    /// from a free region starting right where the block ends. On success the block has to be
+    /// freed with `new_size` (and the original alignment) from then on.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `layout` must be a block `allocate` returned and that isn't freed yet.
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => new_layout,
+            Err(_) => return false,
+        };
+        let (old_size, _) = LinkedListAllocator::size_align(layout);
+        let (adjusted_new_size, _) = LinkedListAllocator::size_align(new_layout);
+        let old_end = ptr as usize + old_size;
+        let new_end = match (ptr as usize).checked_add(adjusted_new_size) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        if new_end < old_end {
+            let freed = old_end - new_end;
+            if freed >= mem::size_of::<ListNode>() {
+                self.add_free_region(new_end, freed);
+            } else {
+                // too small for a ListNode on its own, only works if it can join a free region right behind the block
+                match self.take_region_at(old_end) {
+                    Some(next_size) => self.add_free_region(new_end, freed + next_size),
+                    None => return false,
+                }
+            }
+        } else if new_end > old_end {
+            let needed = new_end - old_end;
+            let next_size = match self.free_region_size_at(old_end) {
+                Some(size) => size,
+                None => return false,
+            };
+            // same rule as in alloc_from_region, whatever's left has to fit a ListNode
+            let excess_size = match next_size.checked_sub(needed) {
+                Some(excess) if excess == 0 || excess >= mem::size_of::<ListNode>() => excess,
+                _ => return false,
+            };
+            self.take_region_at(old_end);
+            if excess_size > 0 {
+                self.add_free_region(new_end, excess_size);
+            }
+        }
+        self.counters.record_realloc_in_place(layout.size(), new_size);
+        true
+    }
+
+    // size of the free region starting exactly at addr, if there is one
+    fn free_region_size_at(&self, addr: usize) -> Option<usize> {
+        self.free_regions()
+            .find(|region| region.start_addr() >= addr)
+            .filter(|region| region.start_addr() == addr)
+            .map(|region| region.size)
+    }
+
+    // removes the free region starting exactly at addr from the list, returns its size
+    fn take_region_at(&mut self, addr: usize) -> Option<usize> {
+        let mut current = &mut self.head;
+        while matches!(&current.next, Some(next) if next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+        if !matches!(&current.next, Some(next) if next.start_addr() == addr) {
+            return None;
+        }
+        let region = current.next.take().unwrap();
+        current.next = region.next.take(); // from A -> B -> C to A -> C
+        Some(region.size)
+    }
+
+    // iterates over the free regions, in address order
+    fn free_regions(&self) -> impl Iterator<Item = &ListNode> {
+        let mut current = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let region = current?;
+            current = region.next.as_deref();
+            Some(region)
+        })
+    }
+
     /// Adjust the given layout so that the resulting allocated memory
     /// region is also capable of storing a `ListNode`.
     ///
@@ -114,32 +279,140 @@ impl LinkedListAllocator {
 }
 
 
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtendHeap for LinkedListAllocator {
+    unsafe fn extend(&mut self, addr: usize, size: usize) {
+        // merges with the last free region if it reaches all the way to the old heap end
+        self.add_free_region(addr, size);
+        self.counters.heap_size += size;
+    }
+}
+
+impl HeapStatistics for LinkedListAllocator {
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            free_bytes: self.free_regions().map(|region| region.size).sum(),
+            largest_free_block: Some(self.free_regions().map(|region| region.size).max().unwrap_or(0)),
+            ..self.counters.stats()
+        }
+    }
+
+    fn for_each_free_region(&self, f: &mut dyn FnMut(usize, usize)) -> bool {
+        for region in self.free_regions() {
+            f(region.start_addr(), region.size);
+        }
+        true
+    }
+}
+
+
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // layout adjustments
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("allow overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+        super::retry_after_reclaim(&layout, || {
+            let mut allocator = self.lock();
+            let mut ptr = allocator.allocate(layout);
+            // out of memory, map more pages at the end of the heap and try again
+            while ptr.is_null() && super::grow_heap(&mut *allocator, &layout) {
+                ptr = allocator.allocate(layout);
             }
-            alloc_start as *mut u8
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.lock().resize_in_place(ptr, layout, new_size) {
+            return ptr;
+        }
+        let new_ptr = super::realloc_by_moving(self, ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.lock().counters.record_realloc_moved();
+        }
+        new_ptr
+    }
+}
+
+// unit tests only build for the host, see host-tests/
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::{HeapStatistics, LinkedListAllocator, ListNode};
+    use alloc::alloc::Layout;
+    use core::mem;
+
+    // 8 byte aligned memory for building free lists in
+    fn region(size: usize) -> alloc::vec::Vec<u64> {
+        alloc::vec![0; size / 8]
+    }
+
+    #[test]
+    fn size_align_fits_a_list_node() {
+        let node = mem::size_of::<ListNode>();
+        let size_align = |size, align| LinkedListAllocator::size_align(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(size_align(1, 1), (node, mem::align_of::<ListNode>()));
+        assert_eq!(size_align(node + 1, 1), (node + 8, 8)); // padded to the node alignment
+        assert_eq!(size_align(100, 64), (128, 64));
+    }
+
+    #[test]
+    fn alloc_from_region_leaves_room_for_list_nodes() {
+        let node = mem::size_of::<ListNode>();
+        let mut memory = region(256);
+        let region = unsafe { &mut *(memory.as_mut_ptr() as *mut ListNode) };
+        *region = ListNode::new(256);
+        let start = region.start_addr();
+
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 256, 8), Ok(start));
+        assert_eq!(LinkedListAllocator::alloc_from_region(region, 256 - node, 8), Ok(start));
+        assert!(LinkedListAllocator::alloc_from_region(region, 257, 8).is_err());
+        // the leftover would be too small to go back on the free list
+        assert!(LinkedListAllocator::alloc_from_region(region, 256 - 8, 8).is_err());
+        // same for padding in front of an aligned allocation, unless there's no padding at all
+        if start % 16 != 0 {
+            assert!(LinkedListAllocator::alloc_from_region(region, 16, 16).is_err());
         } else {
-            ptr::null_mut()
+            assert_eq!(LinkedListAllocator::alloc_from_region(region, 16, 16), Ok(start));
+        }
+    }
+
+    #[test]
+    fn find_region_takes_first_fit_off_the_list() {
+        let mut memory = region(1024);
+        let start = memory.as_mut_ptr() as usize;
+        let mut allocator = LinkedListAllocator::new();
+        unsafe {
+            // two separate regions, 64 and 256 bytes
+            allocator.add_free_region(start, 64);
+            allocator.add_free_region(start + 512, 256);
         }
 
+        let (region, alloc_start) = allocator.find_region(128, 8).unwrap();
+        assert_eq!((region.start_addr(), region.size, alloc_start), (start + 512, 256, start + 512));
+        let (region, _) = allocator.find_region(32, 8).unwrap();
+        assert_eq!(region.start_addr(), start);
+        assert!(allocator.find_region(8, 8).is_none());
     }
 
-    // ToDo maybe implement merging freed blocks to prevent fragmentation ?
-    // Instead of inserting freed memory blocks at the beginning of the linked list on deallocate, it always keeps the list sorted by start address. This way, merging can be performed directly on the deallocate call by examining the addresses and sizes of the two neighbor blocks in the list.
-    // sample implementation here https://github.com/phil-opp/linked-list-allocator/blob/251eb1b0728e9b9cdd1dd6281effa0a6c6d70d63/src/lib.rs#L117
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        //perform layout adjustments
-        let (size, _) = LinkedListAllocator::size_align(layout);
-        self.lock().add_free_region(ptr as usize, size);
+    #[test]
+    fn for_each_free_region_walks_the_free_list() {
+        let mut memory = region(1024);
+        let start = memory.as_mut_ptr() as usize;
+        let mut allocator = LinkedListAllocator::new();
+        unsafe {
+            allocator.add_free_region(start + 512, 256);
+            allocator.add_free_region(start, 64);
+        }
+
+        let mut regions = alloc::vec::Vec::new();
+        assert!(allocator.for_each_free_region(&mut |start, size| regions.push((start, size))));
+        assert_eq!(regions, [(start, 64), (start + 512, 256)]);
     }
 }
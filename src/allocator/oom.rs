@@ -0,0 +1,103 @@
+use super::{heap_size, heap_stats, HeapStatistics, ALLOCATOR, HEAP_LIMIT, HEAP_START};
+use alloc::alloc::Layout;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Called when an allocation can't be satisfied, with the layout that failed.
+/// Returns true if it freed something and the allocation should be tried again.
+pub type ReclaimFn = fn(&Layout) -> bool;
+
+/// What happens when the kernel heap runs out of memory, see `set_oom_policy`.
+#[derive(Clone, Copy)]
+pub enum OomPolicy {
+    /// Print the OOM report and panic, the panic handler then halts the kernel.
+    Halt,
+    /// Call the reclaim callback (e.g. to drop caches) and retry the allocation while it returns true,
+    /// at most `MAX_RECLAIM_ATTEMPTS` times. Halts like `Halt` once it gives up.
+    Reclaim(ReclaimFn),
+}
+
+// a callback that keeps returning true without freeing enough would otherwise retry forever
+pub const MAX_RECLAIM_ATTEMPTS: usize = 8;
+// longer free lists are cut off in the report, the VGA screen is only 25 lines
+const MAX_REPORTED_REGIONS: usize = 16;
+
+static POLICY: Mutex<OomPolicy> = Mutex::new(OomPolicy::Halt);
+// set while the reclaim callback runs, allocations failing inside of it don't reclaim again
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Sets what happens when an allocation fails, the default is `OomPolicy::Halt`.
+///
+/// Only the backends in this module retry, the `alloc-external` heap always halts.
+pub fn set_oom_policy(policy: OomPolicy) {
+    *POLICY.lock() = policy;
+}
+
+// called by allocator::retry_after_reclaim once the heap couldn't grow any further, without any allocator lock held
+pub(super) fn reclaim(layout: &Layout, attempt: usize) -> bool {
+    let callback = match *POLICY.lock() {
+        OomPolicy::Reclaim(callback) if attempt < MAX_RECLAIM_ATTEMPTS => callback,
+        _ => return false,
+    };
+    if RECLAIMING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    let freed = callback(layout);
+    RECLAIMING.store(false, Ordering::SeqCst);
+    freed
+}
+
+/// Prints what the heap looks like after an allocation of `layout` failed, on VGA and serial.
+///
+/// Called by the `alloc_error_handler`. Doesn't allocate, the heap just told us it can't.
+pub fn report(layout: &Layout) {
+    interrupts::without_interrupts(|| {
+        let _ = write_report(&mut *crate::vga_buffer::WRITER.lock(), layout);
+        let _ = write_report(&mut *crate::serial::SERIAL1.lock(), layout);
+    });
+}
+
+fn write_report(out: &mut impl Write, layout: &Layout) -> fmt::Result {
+    let stats = heap_stats();
+    writeln!(out, "\nout of memory: allocating {} bytes (align {}) failed", layout.size(), layout.align())?;
+    writeln!(out, "{}", stats)?;
+    writeln!(
+        out,
+        "heap mapped:        {} of at most {} bytes",
+        heap_size(),
+        HEAP_LIMIT.load(Ordering::SeqCst) - HEAP_START
+    )?;
+    if stats.free_bytes < layout.size() {
+        writeln!(out, "=> heap is exhausted")?;
+    } else if let Some(largest_free_block) = stats.largest_free_block {
+        if largest_free_block >= layout.size() {
+            writeln!(out, "=> largest free block fits the size, but not with this alignment")?;
+        } else {
+            // enough free memory in total means it's just not in one piece
+            writeln!(out, "=> heap is fragmented, {} bytes free but no block large enough", stats.free_bytes)?;
+        }
+    } else {
+        writeln!(out, "=> {} bytes free, but not in a block large enough for it", stats.free_bytes)?;
+    }
+
+    let mut regions = 0;
+    let mut result = Ok(());
+    let has_free_list = ALLOCATOR.lock().for_each_free_region(&mut |start, size| {
+        if regions == 0 {
+            result = result.and_then(|_| writeln!(out, "free regions:"));
+        }
+        if regions < MAX_REPORTED_REGIONS {
+            result = result.and_then(|_| writeln!(out, "  {:#x}..{:#x} ({} bytes)", start, start + size, size));
+        }
+        regions += 1;
+    });
+    result?;
+    if regions > MAX_REPORTED_REGIONS {
+        writeln!(out, "  ... {} more", regions - MAX_REPORTED_REGIONS)?;
+    } else if has_free_list && regions == 0 {
+        writeln!(out, "free regions: none")?;
+    }
+    Ok(())
+}
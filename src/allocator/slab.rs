@@ -0,0 +1,361 @@
+use alloc::alloc::{handle_alloc_error, Layout};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::{fmt, mem};
+
+use super::Locked;
+
+// slabs are whole pages, or a power of two number of them for larger objects
+const PAGE_SIZE: usize = 4096;
+// a slab grows past one page until at least this many objects fit in it
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+struct FreeObject {
+    next: Option<&'static mut FreeObject>,
+}
+
+// which of the cache's lists a slab is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlabList {
+    Partial,
+    Full,
+    Empty,
+}
+
+// header at the start of every slab, the objects follow it
+struct Slab {
+    free: Option<&'static mut FreeObject>,
+    in_use: usize,
+    // the lists are doubly linked, so a slab found by masking an object's address can be moved in O(1)
+    list: SlabList,
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+/// Hands out objects of one size, carved out of slabs of whole pages.
+///
+/// Slabs are aligned to their size, so the slab an object belongs to is found by masking its address.
+/// Slabs with free objects are used first, a slab whose objects are all freed goes back to whoever
+/// provided it (through `deallocate`), except for one empty slab that is kept around so that
+/// allocating and freeing right at a slab boundary doesn't take and release a slab every time.
+///
+/// The cache doesn't allocate slabs itself: when `allocate` returns None, allocate `slab_layout()`
+/// bytes from wherever slabs should come from and hand them over with `add_slab`.
+pub struct RawSlabCache {
+    object_size: usize,
+    first_object_offset: usize,
+    slab_size: usize,
+    partial: *mut Slab, // slabs with at least one free object
+    full: *mut Slab,
+    empty: *mut Slab, // at most one
+    slabs: usize,
+    objects_in_use: usize,
+}
+
+// the slabs are only reachable through the cache, which isn't tied to any thread
+unsafe impl Send for RawSlabCache {}
+
+impl RawSlabCache {
+    /// Creates an empty cache for objects with the given size and alignment, `align` has to be a power of two.
+    pub const fn new(size: usize, align: usize) -> Self {
+        let align = if align > mem::align_of::<FreeObject>() { align } else { mem::align_of::<FreeObject>() };
+        let size = if size > mem::size_of::<FreeObject>() { size } else { mem::size_of::<FreeObject>() };
+        // align_up isn't a const fn
+        let object_size = (size + align - 1) & !(align - 1);
+        let first_object_offset = (mem::size_of::<Slab>() + align - 1) & !(align - 1);
+
+        let mut slab_size = PAGE_SIZE;
+        while (slab_size - first_object_offset) / object_size < MIN_OBJECTS_PER_SLAB {
+            slab_size *= 2;
+        }
+
+        RawSlabCache {
+            object_size,
+            first_object_offset,
+            slab_size,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+            empty: ptr::null_mut(),
+            slabs: 0,
+            objects_in_use: 0,
+        }
+    }
+
+    /// Size and alignment of the memory `add_slab` expects.
+    pub fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.slab_size, self.slab_size).unwrap()
+    }
+
+    /// Size of the objects handed out, the requested size rounded up to the alignment.
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn objects_per_slab(&self) -> usize {
+        (self.slab_size - self.first_object_offset) / self.object_size
+    }
+
+    /// Number of slabs the cache holds, including the cached empty one.
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    pub fn objects_in_use(&self) -> usize {
+        self.objects_in_use
+    }
+
+    pub fn free_objects(&self) -> usize {
+        self.slabs * self.objects_per_slab() - self.objects_in_use
+    }
+
+    /// Takes an object from the slabs the cache already has, None if they're all full.
+    pub fn allocate(&mut self) -> Option<*mut u8> {
+        unsafe {
+            if self.partial.is_null() {
+                if self.empty.is_null() {
+                    return None;
+                }
+                self.move_slab(self.empty, SlabList::Partial);
+            }
+            let slab = self.partial;
+            let object = (*slab).free.take().expect("slab on the partial list without free objects");
+            (*slab).free = object.next.take();
+            (*slab).in_use += 1;
+
+            if (*slab).free.is_none() {
+                // last free object is gone
+                self.move_slab(slab, SlabList::Full);
+            }
+            self.objects_in_use += 1;
+            Some(object as *mut FreeObject as *mut u8)
+        }
+    }
+
+    /// Hands a new slab over to the cache, its objects can be allocated right away.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be unused, and match `slab_layout`.
+    pub unsafe fn add_slab(&mut self, slab: *mut u8) {
+        debug_assert_eq!(slab as usize & (self.slab_size - 1), 0);
+        // thread all objects into the free list, lowest address first
+        let mut free = None;
+        for index in (0..self.objects_per_slab()).rev() {
+            let object = slab.add(self.first_object_offset + index * self.object_size) as *mut FreeObject;
+            object.write(FreeObject { next: free });
+            free = Some(&mut *object);
+        }
+
+        let slab = slab as *mut Slab;
+        slab.write(Slab {
+            free,
+            in_use: 0,
+            list: SlabList::Partial,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+        });
+        self.push(slab, SlabList::Partial);
+        self.slabs += 1;
+    }
+
+    /// Gives an object back to its slab. If that leaves the slab empty and another empty slab
+    /// is already cached, the slab is removed from the cache and returned, to be freed with `slab_layout`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate` on this cache, and not be used anymore.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8) -> Option<*mut u8> {
+        let slab = (ptr as usize & !(self.slab_size - 1)) as *mut Slab;
+        debug_assert!((*slab).list != SlabList::Empty, "freeing an object of an empty slab");
+
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject { next: (*slab).free.take() });
+        (*slab).free = Some(&mut *object);
+        (*slab).in_use -= 1;
+        self.objects_in_use -= 1;
+
+        if (*slab).in_use > 0 {
+            // the slab we just freed to goes first, its memory is the most likely to still be in the CPU cache
+            self.move_slab(slab, SlabList::Partial);
+            None
+        } else if self.empty.is_null() {
+            self.move_slab(slab, SlabList::Empty);
+            None
+        } else {
+            self.unlink(slab);
+            self.slabs -= 1;
+            Some(slab as *mut u8)
+        }
+    }
+
+    /// Removes the cached empty slab, if there is one, to be freed with `slab_layout`.
+    pub fn release_empty_slab(&mut self) -> Option<*mut u8> {
+        let slab = self.empty;
+        if slab.is_null() {
+            return None;
+        }
+        unsafe { self.unlink(slab) };
+        self.slabs -= 1;
+        Some(slab as *mut u8)
+    }
+
+    fn head(&mut self, list: SlabList) -> &mut *mut Slab {
+        match list {
+            SlabList::Partial => &mut self.partial,
+            SlabList::Full => &mut self.full,
+            SlabList::Empty => &mut self.empty,
+        }
+    }
+
+    // puts a slab that isn't on any list at the front of the given one
+    unsafe fn push(&mut self, slab: *mut Slab, list: SlabList) {
+        let head = self.head(list);
+        (*slab).list = list;
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = *head;
+        if !head.is_null() {
+            (**head).prev = slab;
+        }
+        *head = slab;
+    }
+
+    // takes the slab off the list it's on
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            *self.head((*slab).list) = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+
+    unsafe fn move_slab(&mut self, slab: *mut Slab, list: SlabList) {
+        self.unlink(slab);
+        self.push(slab, list);
+    }
+}
+
+/// Object cache for kernel structures of one type that are allocated and freed a lot, e.g. task control blocks.
+///
+/// Slabs come from the kernel heap, objects are handed out as `SlabBox`es which go back to the cache when dropped.
+/// Meant to live in a static, e.g. `static TASKS: SlabCache<Task> = SlabCache::new();` and then `TASKS.alloc(task)`.
+pub struct SlabCache<T> {
+    raw: Locked<RawSlabCache>,
+    _type: PhantomData<T>,
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SlabCache<T> {
+    pub const fn new() -> Self {
+        SlabCache {
+            raw: Locked::new(RawSlabCache::new(mem::size_of::<T>(), mem::align_of::<T>())),
+            _type: PhantomData,
+        }
+    }
+
+    /// Moves `value` into an object from the cache, calls `handle_alloc_error` if no slab can be allocated for it.
+    pub fn alloc(&self, value: T) -> SlabBox<'_, T> {
+        let allocated = self.raw.lock().allocate();
+        let object = match allocated {
+            Some(object) => object,
+            None => {
+                // not locked while allocating, an OOM reclaim callback may want to shrink this cache
+                let layout = self.raw.lock().slab_layout();
+                let slab = unsafe { alloc::alloc::alloc(layout) };
+                if slab.is_null() {
+                    handle_alloc_error(layout);
+                }
+                let mut raw = self.raw.lock();
+                unsafe { raw.add_slab(slab) };
+                raw.allocate().unwrap()
+            }
+        };
+        let object = object as *mut T;
+
+        unsafe { object.write(value) };
+        SlabBox {
+            object: unsafe { NonNull::new_unchecked(object) },
+            cache: self,
+        }
+    }
+
+    /// Number of objects currently allocated from this cache.
+    pub fn objects_in_use(&self) -> usize {
+        self.raw.lock().objects_in_use()
+    }
+
+    /// Number of slabs taken from the heap.
+    pub fn slabs(&self) -> usize {
+        self.raw.lock().slabs()
+    }
+
+    /// Gives the cached empty slab back to the heap.
+    pub fn shrink(&self) {
+        let mut raw = self.raw.lock();
+        let slab = raw.release_empty_slab();
+        let layout = raw.slab_layout();
+        drop(raw);
+        if let Some(slab) = slab {
+            unsafe { alloc::alloc::dealloc(slab, layout) };
+        }
+    }
+
+    unsafe fn free(&self, object: *mut T) {
+        // like in alloc, the heap is only called with the cache unlocked
+        let mut raw = self.raw.lock();
+        let slab = raw.deallocate(object as *mut u8);
+        let layout = raw.slab_layout();
+        drop(raw);
+        if let Some(slab) = slab {
+            alloc::alloc::dealloc(slab, layout);
+        }
+    }
+}
+
+/// An object allocated from a `SlabCache`, like a `Box` that goes back to its cache when dropped.
+pub struct SlabBox<'a, T> {
+    object: NonNull<T>,
+    cache: &'a SlabCache<T>,
+}
+
+// same as Box, the object is owned by the SlabBox alone
+unsafe impl<T: Send> Send for SlabBox<'_, T> {}
+unsafe impl<T: Sync> Sync for SlabBox<'_, T> {}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.free(self.object.as_ptr());
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use super::fixed_size_block::BLOCK_SIZES;
+use core::fmt;
+
+/// Snapshot of how a heap allocator is being used, see `allocator::heap_stats`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// bytes of heap memory the allocator manages
+    pub heap_size: usize,
+    /// bytes requested by allocations that are still live
+    pub bytes_in_use: usize,
+    /// highest `bytes_in_use` so far
+    pub peak_bytes_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// `realloc` calls that resized the allocation where it was
+    pub reallocs_in_place: usize,
+    /// `realloc` calls that had to allocate a new block and copy everything over
+    pub reallocs_moved: usize,
+    /// bytes the allocator could still hand out, including blocks cached for reuse
+    pub free_bytes: usize,
+    /// None if the allocator can't tell, like the linked_list_allocator crate
+    pub largest_free_block: Option<usize>,
+    /// number of free blocks in the slabs of each `fixed_size_block::BLOCK_SIZES` size, only for the fixed size block allocator
+    pub free_blocks_per_size: Option<[usize; BLOCK_SIZES.len()]>,
+}
+
+impl HeapStats {
+    /// How much of the free memory can't be handed out as one block, from 0.0 (a single free block)
+    /// to almost 1.0 (lots of tiny scattered pieces). None if `largest_free_block` is unknown.
+    pub fn fragmentation(&self) -> Option<f32> {
+        let largest_free_block = self.largest_free_block?;
+        if self.free_bytes == 0 {
+            Some(0.0)
+        } else {
+            Some(1.0 - largest_free_block as f32 / self.free_bytes as f32)
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap size:          {} bytes", self.heap_size)?;
+        writeln!(f, "in use:             {} bytes (peak {} bytes)", self.bytes_in_use, self.peak_bytes_in_use)?;
+        writeln!(f, "allocations/frees:  {}/{}", self.allocations, self.frees)?;
+        writeln!(f, "reallocs in place:  {} (moved {})", self.reallocs_in_place, self.reallocs_moved)?;
+        write!(f, "free:               {} bytes", self.free_bytes)?;
+        if let (Some(largest_free_block), Some(fragmentation)) = (self.largest_free_block, self.fragmentation()) {
+            write!(f, "\nlargest free block: {} bytes", largest_free_block)?;
+            write!(f, "\nfragmentation:      {:.2}", fragmentation)?;
+        }
+        if let Some(free_blocks) = self.free_blocks_per_size {
+            write!(f, "\nfree blocks per size:")?;
+            for (size, count) in BLOCK_SIZES.iter().zip(free_blocks.iter()) {
+                write!(f, " {}:{}", size, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Implemented by all heap allocators in this module.
+pub trait HeapStatistics {
+    fn stats(&self) -> HeapStats;
+
+    /// Calls `f` with the start address and size of every free region, in address order.
+    ///
+    /// Returns false if the allocator doesn't keep a list of free regions.
+    fn for_each_free_region(&self, _f: &mut dyn FnMut(usize, usize)) -> bool {
+        false
+    }
+}
+
+// usage counters every allocator keeps and copies into its HeapStats
+#[derive(Debug, Clone, Copy)]
+pub(super) struct AllocCounters {
+    pub heap_size: usize,
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub reallocs_in_place: usize,
+    pub reallocs_moved: usize,
+}
+
+impl AllocCounters {
+    pub const fn new() -> Self {
+        AllocCounters {
+            heap_size: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            allocations: 0,
+            frees: 0,
+            reallocs_in_place: 0,
+            reallocs_moved: 0,
+        }
+    }
+
+    pub fn record_alloc(&mut self, size: usize) {
+        self.allocations += 1;
+        self.bytes_in_use += size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+
+    pub fn record_free(&mut self, size: usize) {
+        self.frees += 1;
+        self.bytes_in_use -= size;
+    }
+
+    pub fn record_realloc_in_place(&mut self, old_size: usize, new_size: usize) {
+        self.reallocs_in_place += 1;
+        self.bytes_in_use = self.bytes_in_use - old_size + new_size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+
+    // the new block and freeing the old one are recorded by alloc and dealloc
+    pub fn record_realloc_moved(&mut self) {
+        self.reallocs_moved += 1;
+    }
+
+    /// HeapStats with the counters filled in, and nothing about free memory yet
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            heap_size: self.heap_size,
+            bytes_in_use: self.bytes_in_use,
+            peak_bytes_in_use: self.peak_bytes_in_use,
+            allocations: self.allocations,
+            frees: self.frees,
+            reallocs_in_place: self.reallocs_in_place,
+            reallocs_moved: self.reallocs_moved,
+            ..HeapStats::default()
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use super::Locked;
+use alloc::alloc::{GlobalAlloc, Layout};
+
+// how many live allocations are remembered individually, allocations beyond that are only counted
+const TABLE_SIZE: usize = 512;
+// return addresses recorded per allocation, starting from the allocator's caller
+pub const CALLER_DEPTH: usize = 4;
+
+/// A live allocation, as recorded by `TrackingAllocator`.
+#[derive(Debug, Clone, Copy)]
+pub struct LiveAllocation {
+    pub ptr: usize,
+    pub size: usize,
+    pub align: usize,
+    /// incremented for every allocation, compare with `Mark::sequence` to tell when it was made
+    pub sequence: u64,
+    /// return addresses up the call stack, 0 where none could be captured
+    /// (only filled in with the `alloc-tracking-callers` feature)
+    pub callers: [usize; CALLER_DEPTH],
+}
+
+/// Live allocation counters at one point in time, see `TrackingAllocator::mark`.
+#[derive(Debug, Clone, Copy)]
+pub struct Mark {
+    pub sequence: u64,
+    pub live_allocations: usize,
+    pub live_bytes: usize,
+}
+
+struct Table {
+    entries: [Option<LiveAllocation>; TABLE_SIZE],
+    next_sequence: u64,
+    live_allocations: usize,
+    live_bytes: usize,
+    // live allocations that didn't fit in `entries`
+    untracked: usize,
+}
+
+/// Keeps a record of every live allocation made through the wrapped allocator.
+///
+/// Enabled with the `alloc-tracking` feature, `test_runner` then uses it to fail tests that leak.
+pub struct TrackingAllocator<A: 'static> {
+    inner: &'static A,
+    table: Locked<Table>,
+}
+
+impl<A: 'static> TrackingAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        TrackingAllocator {
+            inner,
+            table: Locked::new(Table {
+                entries: [None; TABLE_SIZE],
+                next_sequence: 0,
+                live_allocations: 0,
+                live_bytes: 0,
+                untracked: 0,
+            }),
+        }
+    }
+
+    /// Current live allocation counters, allocations made after this are newer than `Mark::sequence`.
+    pub fn mark(&self) -> Mark {
+        let table = self.table.lock();
+        Mark {
+            sequence: table.next_sequence,
+            live_allocations: table.live_allocations,
+            live_bytes: table.live_bytes,
+        }
+    }
+
+    /// Calls `f` for every allocation made since `mark` that is still live.
+    ///
+    /// The table is locked while `f` runs, so `f` must not allocate.
+    pub fn for_each_since<F: FnMut(&LiveAllocation)>(&self, mark: &Mark, mut f: F) {
+        let table = self.table.lock();
+        table
+            .entries
+            .iter()
+            .flatten()
+            .filter(|allocation| allocation.sequence >= mark.sequence)
+            .for_each(|allocation| f(allocation));
+    }
+
+    /// Number of live allocations that didn't fit in the table and so won't show up in `for_each_since`.
+    pub fn untracked(&self) -> usize {
+        self.table.lock().untracked
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for TrackingAllocator<A> {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            return ptr;
+        }
+
+        let callers = callers();
+        let mut table = self.table.lock();
+        let allocation = LiveAllocation {
+            ptr: ptr as usize,
+            size: layout.size(),
+            align: layout.align(),
+            sequence: table.next_sequence,
+            callers,
+        };
+        table.next_sequence += 1;
+        table.live_allocations += 1;
+        table.live_bytes += layout.size();
+        match table.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => *entry = Some(allocation),
+            None => table.untracked += 1,
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        {
+            let mut table = self.table.lock();
+            table.live_allocations -= 1;
+            table.live_bytes -= layout.size();
+            let entry = table
+                .entries
+                .iter_mut()
+                .find(|entry| entry.map_or(false, |allocation| allocation.ptr == ptr as usize));
+            match entry {
+                Some(entry) => *entry = None,
+                None => table.untracked -= 1,
+            }
+        }
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+// walks the frame pointer chain, only reliable when built with `-C force-frame-pointers=yes`
+#[cfg(feature = "alloc-tracking-callers")]
+#[inline(always)] // so that the walk starts at the frame of `alloc`
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let (mut rbp, rsp): (usize, usize);
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+    }
+    // frames further up the stack sit at higher addresses, stop as soon as rbp doesn't look like
+    // a frame pointer anymore so we never read outside of the current stack
+    const MAX_STACK_WALK: usize = 64 * 1024;
+    for caller in callers.iter_mut() {
+        if rbp < rsp || rbp - rsp > MAX_STACK_WALK || rbp % 8 != 0 {
+            break;
+        }
+        let frame = rbp as *const usize;
+        unsafe {
+            *caller = frame.add(1).read(); // return address is right above the saved rbp
+            let next_rbp = frame.read();
+            if next_rbp <= rbp {
+                break;
+            }
+            rbp = next_rbp;
+        }
+    }
+    callers
+}
+
+#[cfg(not(feature = "alloc-tracking-callers"))]
+fn callers() -> [usize; CALLER_DEPTH] {
+    [0; CALLER_DEPTH]
+}
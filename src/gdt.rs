@@ -1,4 +1,7 @@
+use crate::memory::KernelStack;
+use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::tss::TaskStateSegment;
@@ -7,19 +10,33 @@ use x86_64::VirtAddr;
 //define that the 0th IST entry is the double fault stack, i.e. safe stack for pushing exception frame when StackOverflow prevents using normal stack for Double Fault handler
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// the page fault handler gets a stack of its own too, so that faults on stacks whose pages are mapped on first touch
+// (`Backing::Lazy`) don't need room on the faulting stack. It must not fault itself: a nested page fault would start
+// over at the top of the same stack.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+// every IST entry in use, with the name of its stack in overflow reports
+const IST_STACKS: &[(u16, &str)] = &[(DOUBLE_FAULT_IST_INDEX, "double fault IST"), (PAGE_FAULT_IST_INDEX, "page fault IST")];
+const IST_STACK_PAGES: u64 = 5;
+
+// the CPU reads the IST entries from the TSS every time it switches stacks, so they can still be changed after load_tss
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// only written by init_ist_stacks, with interrupts disabled
+unsafe impl Sync for Tss {}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE]; // this is just u8 array of size STACK_SIZE, initialized with zeros
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            // we return stack_end address, because stack grows `backwards` (from high to low)
-            stack_end
-        };
-        tss
+        // static boot stacks without a guard page, until init_ist_stacks replaces them once paging is set up
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACKS: [[u8; STACK_SIZE]; IST_STACKS.len()] = [[0; STACK_SIZE]; IST_STACKS.len()];
+        for (stack, &(index, _)) in IST_STACKS.iter().enumerate() {
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACKS[stack] });
+            // the entry is the end address, because the stack grows `backwards` (from high to low)
+            tss.interrupt_stack_table[index as usize] = stack_start + STACK_SIZE;
+        }
+        Tss(UnsafeCell::new(tss))
     };
 }
 
@@ -27,7 +44,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (
             gdt,
             Selectors {
@@ -53,3 +70,17 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Switches every IST entry to a `KernelStack`, so that overflowing one hits a guard page instead of the statics next to it.
+///
+/// Called by `memory::init_kernel_paging`, the stacks are mapped for good.
+pub fn init_ist_stacks() {
+    for &(index, name) in IST_STACKS {
+        let top = KernelStack::new(IST_STACK_PAGES, name)
+            .expect("mapping IST stack failed")
+            .leak();
+        interrupts::without_interrupts(|| unsafe {
+            (*TSS.0.get()).interrupt_stack_table[index as usize] = top;
+        });
+    }
+}
@@ -5,6 +5,9 @@ use pic8259_simple::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+pub mod apic;
+pub use apic::{Apic, ApicError};
+
 /*
 just for reference full IDT struct:
 
@@ -39,10 +42,12 @@ pub struct InterruptDescriptorTable {
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // only raised by the APIC, and doesn't get an EOI
+    Spurious = 0xff,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
     fn as_usize(self) -> usize {
@@ -67,7 +72,14 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
+        idt[usize::from(PIC_1_OFFSET + 7)].set_handler_fn(spurious_pic_1_handler);
+        idt[usize::from(PIC_2_OFFSET + 7)].set_handler_fn(spurious_pic_2_handler);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
         idt
     };
 }
@@ -83,6 +95,13 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // a stack running into its guard page ends up in the page fault handler, which has its own IST stack. This is for
+    // when the page fault can't be delivered at all, e.g. with an IDT whose page fault entry doesn't switch stacks
+    if let Some(stack) = crate::memory::vma::guard_page_owner(Cr2::read()) {
+        panic!("EXCEPTION: stack overflow in stack {}\n{:#?}", stack, stack_frame);
+    }
     panic!("BOOM! EXCEPTION: Double Fault\n{:#?} ", stack_frame);
 }
 
@@ -97,18 +116,150 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+// set up by init_apic, the PIC is used until then (and for good if there's no APIC)
+static APIC: spin::Mutex<Option<Apic>> = spin::Mutex::new(None);
+
+/// What interrupt handlers signal the end of an interrupt to: the 8259 PIC or the APIC, whichever is in use.
+pub trait InterruptController {
+    /// Lets the controller deliver the next interrupt of `index`, or of lower priority.
+    fn end_of_interrupt(&mut self, index: InterruptIndex);
+}
+
+impl InterruptController for ChainedPics {
+    fn end_of_interrupt(&mut self, index: InterruptIndex) {
+        unsafe { self.notify_end_of_interrupt(index.as_u8()) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
+
+/// Controller interrupts currently go through.
+pub fn controller() -> Controller {
+    if with_apic(|_| ()).is_some() {
+        Controller::Apic
+    } else {
+        Controller::Pic
+    }
+}
+
+/// Masks every IRQ of the legacy PIC and sets up the local APIC and IO-APIC instead (see `Apic::init`).
+///
+/// Needs kernel paging, for mapping the APIC registers. On error, e.g. `ApicError::NotSupported` when CPUID reports
+/// no APIC, the PIC stays in use.
+pub fn init_apic() -> Result<(), ApicError> {
+    init_apic_with(apic::is_supported())
+}
+
+/// Same as `init_apic`, with what CPUID says about the local APIC passed in, so that falling back to the PIC
+/// can be tested on a machine that has an APIC.
+pub fn init_apic_with(cpu_has_apic: bool) -> Result<(), ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pic_masks = [Port::<u8>::new(PIC_1_DATA), Port::new(PIC_2_DATA)];
+        let saved = unsafe { [pic_masks[0].read(), pic_masks[1].read()] };
+        for port in pic_masks.iter_mut() {
+            unsafe { port.write(0xff) };
+        }
+        let apic = if cpu_has_apic { Apic::init() } else { Err(ApicError::NotSupported) };
+        match apic {
+            Ok(apic) => {
+                *APIC.lock() = Some(apic);
+                Ok(())
+            }
+            Err(err) => {
+                for (port, &mask) in pic_masks.iter_mut().zip(saved.iter()) {
+                    unsafe { port.write(mask) };
+                }
+                Err(err)
+            }
+        }
+    })
+}
+
+/// Runs `f` with the APIC, None if interrupts still go through the PIC.
+pub fn with_apic<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut Apic) -> R,
+{
+    // the interrupt handlers lock it too
+    x86_64::instructions::interrupts::without_interrupts(|| APIC.lock().as_mut().map(f))
+}
+
 fn send_end_of_interrupt(ii: InterruptIndex) {
-    unsafe {
-        //PIC expects an explicit “end of interrupt” (EOI) signal from our interrupt handler.
-        PICS.lock().notify_end_of_interrupt(ii.as_u8())
-    };
+    //PIC expects an explicit “end of interrupt” (EOI) signal from our interrupt handler, so does the APIC.
+    match APIC.lock().as_mut() {
+        Some(apic) => apic.end_of_interrupt(ii),
+        None => PICS.lock().end_of_interrupt(ii),
+    }
+}
+
+// command ports of the two PICs, their end of interrupt command, and OCW3 to read the in-service register
+// from the command port next
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_EOI: u8 = 0x20;
+const PIC_READ_ISR: u8 = 0x0b;
+// data ports of the two PICs, writing them sets the IRQ masks
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interrupts since boot, whichever controller raised them.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
     print!(".");
+    TICKS.fetch_add(1, Ordering::SeqCst);
 
     send_end_of_interrupt(InterruptIndex::Timer);
 }
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    // the APIC didn't mark anything as in service, so there's nothing to acknowledge
+}
+
+// the PICs raise their lowest priority IRQ (7 and 15) when an interrupt goes away before it's delivered,
+// also while masked. Nothing is in service then, and an EOI could acknowledge a real interrupt instead. These vectors
+// only come from the PICs, so real interrupts on them get the PIC's EOI whichever controller is in use.
+extern "x86-interrupt" fn spurious_pic_1_handler(_stack_frame: &mut InterruptStackFrame) {
+    if irq_7_in_service(PIC_1_COMMAND) {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 7) };
+    }
+}
+
+extern "x86-interrupt" fn spurious_pic_2_handler(_stack_frame: &mut InterruptStackFrame) {
+    if irq_7_in_service(PIC_2_COMMAND) {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 7) };
+    } else {
+        // the first PIC did see a real interrupt on the cascade line, only the second one gets no EOI
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+    }
+}
+
+// whether the PIC with this command port has its IRQ 7 in service (bit 7 of its ISR), i.e. it wasn't spurious
+fn irq_7_in_service(command: u16) -> bool {
+    let mut port = Port::<u8>::new(command);
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read() & (1 << 7) != 0
+    }
+}
+
+#[test_case]
+fn test_no_irq_7_in_service() {
+    // only set while a real IRQ 7 or 15 is handled
+    assert!(!irq_7_in_service(PIC_1_COMMAND));
+    assert!(!irq_7_in_service(PIC_2_COMMAND));
+}
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use spin::Mutex;
 
@@ -151,6 +302,19 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    // a page of a lazy region gets its frame now, returning runs the faulting instruction again
+    if crate::memory::vma::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+    // same for the first write to a copy-on-write page
+    if crate::memory::cow::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+
+    // running on its own IST stack, the handler gets to see overflows into a guard page itself
+    if let Some(stack) = crate::memory::vma::guard_page_owner(Cr2::read()) {
+        panic!("EXCEPTION: stack overflow in stack {}\n{:#?}", stack, stack_frame);
+    }
     println!("Exception: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
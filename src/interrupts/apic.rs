@@ -0,0 +1,134 @@
+use super::{InterruptController, InterruptIndex};
+use crate::memory::{map_mmio, MmioRegion, VmaError};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers, offsets into its page
+const ID: u64 = 0x20;
+const TASK_PRIORITY: u64 = 0x80;
+const EOI: u64 = 0xb0;
+const SPURIOUS_VECTOR: u64 = 0xf0;
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+// not calibrated, roughly the 18 Hz of the PIT in QEMU
+const TIMER_INITIAL: u32 = 0x40_0000;
+
+/// Where the IO-APIC usually is. The MADT would say for sure, but we don't parse ACPI tables yet.
+pub const IO_APIC_ADDR: u64 = 0xfec0_0000;
+
+// IO-APIC registers, selected through IOREGSEL and accessed through IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// IO-APIC input the PS/2 keyboard is wired to, the same as its legacy IRQ.
+pub const KEYBOARD_IRQ: u8 = 1;
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUID says there's no local APIC.
+    NotSupported,
+    /// The registers couldn't be mapped, e.g. `memory::init_kernel_paging` wasn't called yet.
+    MapFailed(VmaError),
+}
+
+/// Whether the CPU has a local APIC, according to CPUID.
+pub fn is_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// The local APIC of the CPU we run on, and the IO-APIC routing device interrupts to it.
+pub struct Apic {
+    local: MmioRegion,
+    io: MmioRegion,
+}
+
+impl Apic {
+    /// Maps and enables the local APIC and IO-APIC, with the local APIC timer firing `InterruptIndex::Timer`
+    /// periodically and the keyboard routed to `InterruptIndex::Keyboard`. All other IO-APIC inputs stay masked.
+    ///
+    /// The legacy PIC has to be masked already, or it keeps raising interrupts of its own.
+    pub fn init() -> Result<Self, ApicError> {
+        if !is_supported() {
+            return Err(ApicError::NotSupported);
+        }
+        let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+        let local = map_mmio(PhysAddr::new(base & 0x000f_ffff_ffff_f000), 4096).map_err(ApicError::MapFailed)?;
+        let io = map_mmio(PhysAddr::new(IO_APIC_ADDR), 0x20).map_err(ApicError::MapFailed)?;
+        let mut apic = Apic { local, io };
+        // the firmware normally leaves it enabled, but the global enable can't be turned back on without a reset
+        unsafe { Msr::new(IA32_APIC_BASE).write(base | APIC_BASE_ENABLE) };
+
+        apic.local.write::<u32>(TASK_PRIORITY, 0);
+        apic.local.write(SPURIOUS_VECTOR, SOFTWARE_ENABLE | u32::from(InterruptIndex::Spurious.as_u8()));
+        apic.local.write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        apic.local.write(LVT_TIMER, TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()));
+        apic.local.write(TIMER_INITIAL_COUNT, TIMER_INITIAL);
+
+        for irq in 0..apic.io_apic_inputs() {
+            apic.set_redirection(irq, REDIRECTION_MASKED);
+        }
+        // fixed delivery to this CPU, edge triggered, active high
+        let destination = u64::from(apic.id()) << 56;
+        apic.set_redirection(KEYBOARD_IRQ, destination | u64::from(InterruptIndex::Keyboard.as_u8()));
+        Ok(apic)
+    }
+
+    /// ID of the local APIC, what the IO-APIC sends interrupts to.
+    pub fn id(&self) -> u8 {
+        (self.local.read::<u32>(ID) >> 24) as u8
+    }
+
+    /// Current value of the timer, counting down from its initial count.
+    pub fn timer_count(&self) -> u32 {
+        self.local.read(TIMER_CURRENT_COUNT)
+    }
+
+    /// Number of inputs (IRQs) the IO-APIC has.
+    pub fn io_apic_inputs(&mut self) -> u8 {
+        ((self.read_io_apic(IO_APIC_VERSION) >> 16) & 0xff) as u8 + 1
+    }
+
+    /// Redirection table entry for `irq`: vector, delivery mode, mask bit and destination.
+    pub fn redirection(&mut self, irq: u8) -> u64 {
+        let index = IO_APIC_REDIRECTION_TABLE + 2 * u32::from(irq);
+        u64::from(self.read_io_apic(index)) | u64::from(self.read_io_apic(index + 1)) << 32
+    }
+
+    fn set_redirection(&mut self, irq: u8, entry: u64) {
+        let index = IO_APIC_REDIRECTION_TABLE + 2 * u32::from(irq);
+        // masked while half written
+        self.write_io_apic(index, REDIRECTION_MASKED as u32);
+        self.write_io_apic(index + 1, (entry >> 32) as u32);
+        self.write_io_apic(index, entry as u32);
+    }
+
+    fn read_io_apic(&mut self, register: u32) -> u32 {
+        self.io.write(IOREGSEL, register);
+        self.io.read(IOWIN)
+    }
+
+    fn write_io_apic(&mut self, register: u32, value: u32) {
+        self.io.write(IOREGSEL, register);
+        self.io.write(IOWIN, value);
+    }
+}
+
+impl InterruptController for Apic {
+    fn end_of_interrupt(&mut self, _index: InterruptIndex) {
+        self.local.write::<u32>(EOI, 0);
+    }
+}
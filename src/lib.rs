@@ -6,6 +6,8 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(allocator_api)]
+#![cfg_attr(feature = "alloc-tracking-callers", feature(asm))]
 
 use core::panic::PanicInfo;
 #[cfg(test)]
@@ -28,7 +30,12 @@ where
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>()); // any::type_name https://doc.rust-lang.org/stable/core/any/fn.type_name.html
+        #[cfg(feature = "alloc-tracking")]
+        let mark = allocator::allocation_mark();
         self();
+        // with allocation tracking on, a test that leaves allocations behind fails
+        #[cfg(feature = "alloc-tracking")]
+        allocator::check_leaks(mark);
         serial_println!("[ok]");
     }
 }
@@ -88,6 +95,10 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 pub fn init() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    // without it the NO_EXECUTE page flag is a reserved bit, memory::init and the heap rely on it
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() }
@@ -107,5 +118,7 @@ pub fn hlt_loop() -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    // the reclaim callback, if there is one, already gave up
+    allocator::oom::report(&layout);
     panic!("Allocation error: {:?}", layout)
 }
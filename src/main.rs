@@ -50,9 +50,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        unsafe { memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::report::print_boot_memory(&boot_info.memory_map);
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap allocation failed");
+    // from here on the heap maps more pages by itself when it runs out
+    memory::init_kernel_paging(mapper, frame_allocator);
+    // interrupts go through the local APIC and IO-APIC from here on, unless the CPU has none
+    if let Err(err) = mini_os::interrupts::init_apic() {
+        println!("staying with the 8259 PIC: {:?}", err);
+    }
     let x = Box::new(32);
     println!("heap_value at {:p}", x); // {:p} pointer formatting https://doc.rust-lang.org/core/fmt/trait.Pointer.html
 
@@ -70,6 +77,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("current referece count is {}", Rc::strong_count(&cloned_reference));
     core::mem::drop(reference_counted);
     println!("current referece count is {}", Rc::strong_count(&cloned_reference));
+
+    mini_os::serial_println!("{}", allocator::heap_stats());
+    memory::report::print_frame_counts();
 /*
 
 
@@ -3,6 +3,26 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
+pub use address_space::{AddressSpace, AddressSpaceError};
+pub mod cow;
+pub mod dma;
+pub use dma::DmaBuffer;
+pub mod dump;
+pub mod frame_allocator;
+pub use frame_allocator::{BitmapFrameAllocator, BuddyFrameAllocator, Zone};
+pub mod huge_page;
+pub use huge_page::{translate, HugeFrameAllocator, HugePageBuffer, HugePageBufferError, Translation};
+pub mod kernel_sections;
+pub use kernel_sections::Section;
+pub mod mmio;
+pub use mmio::{map_mmio, MmioRegion};
+pub mod report;
+pub mod stack;
+pub use stack::KernelStack;
+pub mod vma;
+pub use vma::{Backing, Vma, VmaError};
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3; // Cr3 points to level 4 page table
 
@@ -17,8 +37,65 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 }
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
+    KERNEL_LEVEL_4_FRAME.store(x86_64::registers::control::Cr3::read().0.start_address().as_u64(), Ordering::SeqCst);
+    // read-only pages have to fault on kernel writes too, or copy-on-write pages would be written in place
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    let mut mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+    // NO_EXECUTE is a reserved bit until NXE is on, setting it would fault on the next access
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE), "mini_os::init has to be called before memory::init");
+    kernel_sections::protect(&mut mapper, physical_memory_offset).expect("couldn't remap the kernel W^X");
+    mapper
+}
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// where the bootloader mapped all of physical memory, set by init
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Virtual address of `addr` in the bootloader's mapping of all physical memory, `init` has to be called first.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) + addr.as_u64())
+}
+
+// the level 4 table the bootloader left in CR3, set by init
+static KERNEL_LEVEL_4_FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// Level 4 table the kernel runs on outside of any `AddressSpace`, `init` has to be called first.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_FRAME.load(Ordering::SeqCst)))
+}
+
+use spin::Mutex;
+
+// page table and frame allocator handed over to the kernel once boot is done, for code that can't get them
+// passed in explicitly (e.g. the heap growing itself from inside the global allocator)
+static KERNEL_PAGING: Mutex<Option<(OffsetPageTable<'static>, BitmapFrameAllocator)>> = Mutex::new(None);
+
+/// Makes `mapper` and `frame_allocator` available through `with_kernel_paging`, and moves the IST entries
+/// to guard-paged stacks (see `gdt::init_ist_stacks`).
+///
+/// Should be called once, after `allocator::init_heap`, with the same mapper and frame allocator.
+pub fn init_kernel_paging(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    *KERNEL_PAGING.lock() = Some((mapper, frame_allocator));
+    crate::gdt::init_ist_stacks();
+}
+
+/// Runs `f` with the kernel page table and frame allocator, returns None if `init_kernel_paging` wasn't called yet.
+///
+/// `f` must not allocate on the heap, since the heap itself calls this function when it needs to grow.
+pub fn with_kernel_paging<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+{
+    // try_lock: if we already hold the lock further up the stack, waiting on it would spin forever
+    let mut paging = KERNEL_PAGING.try_lock()?;
+    let (mapper, frame_allocator) = paging.as_mut()?;
+    Some(f(mapper, frame_allocator))
 }
 
 
@@ -34,6 +111,7 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 
 //frame allocator that returns usable frame using info from bootloader's memory_map
+// every allocation walks the memory map from the start and frames can't be freed, see BitmapFrameAllocator for the one the kernel uses
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -69,6 +147,7 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+// from the paging tutorial, drivers map device memory with `map_mmio`
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
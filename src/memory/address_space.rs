@@ -0,0 +1,311 @@
+use super::cow::{self, COW};
+use super::report::{self, PageTableFrames, Purpose};
+use super::{kernel_level_4_frame, phys_to_virt, translate, with_kernel_paging, BitmapFrameAllocator, Translation};
+use core::ptr;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size2MiB,
+        Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const ENTRIES: usize = 512;
+// level 4 entries from here on map the upper half, 0xffff_8000_0000_0000 and up
+const UPPER_HALF: usize = 256;
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// `memory::init_kernel_paging` wasn't called yet, or the kernel page table is in use further up the stack.
+    NoKernelPaging,
+    FrameAllocationFailed,
+    /// The address is under a level 4 entry shared with the kernel, user mappings can't go there.
+    KernelRange(VirtAddr),
+    MapFailed(MapToError<Size4KiB>),
+    UnmapFailed(UnmapError),
+    /// `cow::MAX_SHARED_FRAMES` frames are shared already.
+    TooManySharedFrames,
+}
+
+/// Page tables with a level 4 table of their own, e.g. for a user program.
+///
+/// The kernel's level 4 entries are shared: all of the upper half, plus whatever the bootloader mapped in the lower
+/// half (kernel image, physical memory, heap), so the kernel keeps running after `activate`. Kernel mappings created
+/// later show up everywhere as long as they're in the upper half (e.g. `vma::map`), the rest of the lower half is
+/// private to this address space. Its page tables and frames are freed when it's dropped, frames shared with
+/// clones (see `clone_cow`) once the last address space using them is gone.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    // level 4 entries pointing at the kernel's tables, never changed or freed through this address space
+    shared: [bool; ENTRIES],
+}
+
+impl AddressSpace {
+    /// Allocates a level 4 table that shares the kernel's entries and has nothing else mapped.
+    ///
+    /// The first call also gives every unused upper half entry of the kernel's table a level 3 table, so that kernel
+    /// mappings made later show up in every address space. That's up to 256 tables (1 MiB), allocated for good.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        with_kernel_paging(|mapper, frame_allocator| {
+            let kernel_table = mapper.level_4_table();
+            fill_upper_half(kernel_table, frame_allocator)?;
+
+            let level_4_frame = allocate_table(frame_allocator)?;
+            let table = unsafe { table_mut(level_4_frame) };
+            let mut shared = [false; ENTRIES];
+            for (index, entry) in kernel_table.iter().enumerate() {
+                if !entry.is_unused() {
+                    table[index] = entry.clone();
+                    shared[index] = true;
+                }
+            }
+            Ok(AddressSpace { level_4_frame, shared })
+        })
+        .unwrap_or(Err(AddressSpaceError::NoKernelPaging))
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Whether `addr` can be mapped with `map_user`, i.e. isn't under a level 4 entry shared with the kernel.
+    pub fn is_private(&self, addr: VirtAddr) -> bool {
+        !self.shared[usize::from(addr.p4_index())]
+    }
+
+    /// Maps `page` to a new zeroed frame, accessible from user mode with `flags` (`PRESENT` and `USER_ACCESSIBLE`
+    /// are added).
+    pub fn map_user(&mut self, page: Page, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        self.check_private(page.start_address())?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        with_kernel_paging(|_, frame_allocator| {
+            let frame = allocate_zeroed_frame(frame_allocator)?;
+            let table_frames = &mut PageTableFrames(&mut *frame_allocator);
+            match unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags(), table_frames) } {
+                Ok(flush) if active => flush.flush(),
+                // not in the TLB, CR3 was written since this address space was last active
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(AddressSpaceError::MapFailed(err));
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NoKernelPaging))
+    }
+
+    /// Unmaps a page mapped by `map_user` and frees its frame, unless a clone still uses it.
+    ///
+    /// Unsafe because nothing may use the page anymore.
+    pub unsafe fn unmap_user(&mut self, page: Page) -> Result<(), AddressSpaceError> {
+        self.check_private(page.start_address())?;
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        let (frame, flush) = mapper.unmap(page).map_err(AddressSpaceError::UnmapFailed)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        if cow::release(frame) {
+            with_kernel_paging(|_, frame_allocator| frame_allocator.deallocate_frame(frame))
+                .ok_or(AddressSpaceError::NoKernelPaging)?;
+        }
+        Ok(())
+    }
+
+    /// Copy of this address space that shares all of its private 4 KiB pages copy-on-write.
+    ///
+    /// Writable pages become read-only and marked `cow::COW` in both, the first write to one of them gets the
+    /// writer a copy of the frame (see `cow::handle_page_fault`). Read-only pages just stay shared.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let mut clone = AddressSpace::new()?;
+        let clone_shared = clone.shared;
+        let mut mapper = unsafe { clone.mapper() };
+        let result = with_kernel_paging(|_, frame_allocator| unsafe {
+            for (index, entry) in table_mut(self.level_4_frame).iter().enumerate() {
+                if self.shared[index] || !entry.flags().contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                // the kernel mapped something there since this address space was created
+                if clone_shared[index] {
+                    return Err(AddressSpaceError::KernelRange(VirtAddr::new((index as u64) << 39)));
+                }
+                for_each_page(entry.frame().unwrap(), 3, (index as u64) << 39, &mut |page, entry| {
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    if !cow::share(frame) {
+                        return Err(AddressSpaceError::TooManySharedFrames);
+                    }
+                    if entry.flags().contains(PageTableFlags::WRITABLE) {
+                        entry.set_flags((entry.flags() - PageTableFlags::WRITABLE) | COW);
+                    }
+                    let table_frames = &mut PageTableFrames(&mut *frame_allocator);
+                    match mapper.map_to_with_table_flags(page, frame, entry.flags(), table_flags(), table_frames) {
+                        Ok(flush) => flush.ignore(),
+                        Err(err) => {
+                            cow::release(frame);
+                            return Err(AddressSpaceError::MapFailed(err));
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NoKernelPaging));
+        // pages that were writable a moment ago may still be in the TLB
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        // on error the clone frees what it got so far when it's dropped
+        result.map(|_| clone)
+    }
+
+    /// Translates `addr` through this address space's page tables, whether it's active or not.
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<Translation> {
+        translate(&unsafe { self.mapper() }, addr)
+    }
+
+    /// Whether CR3 points at this address space.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches CR3 to this address space.
+    ///
+    /// Unsafe because references into the private part of the previous address space become dangling,
+    /// and the address space must stay alive while it's active (dropping it switches back to the kernel's).
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    fn check_private(&self, addr: VirtAddr) -> Result<(), AddressSpaceError> {
+        if self.is_private(addr) {
+            Ok(())
+        } else {
+            Err(AddressSpaceError::KernelRange(addr))
+        }
+    }
+
+    // unsafe because the mapper must only be used for the private part, the shared tables belong to the kernel's mapper
+    unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        OffsetPageTable::new(table_mut(self.level_4_frame), phys_to_virt(PhysAddr::new(0)))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { activate_kernel() };
+        }
+        with_kernel_paging(|_, frame_allocator| unsafe {
+            let table = table_mut(self.level_4_frame);
+            for (index, entry) in table.iter().enumerate() {
+                if !self.shared[index] && !entry.is_unused() {
+                    free_table(entry.frame().expect("huge page in level 4 table"), 3, frame_allocator);
+                }
+            }
+            frame_allocator.deallocate_frame(self.level_4_frame);
+            report::count_freed(Purpose::PageTables, 1);
+        })
+        .expect("kernel paging not initialized");
+    }
+}
+
+/// Switches CR3 back to the kernel's own level 4 table.
+///
+/// Unsafe for the same reasons as `AddressSpace::activate`.
+pub unsafe fn activate_kernel() {
+    let (_, flags) = Cr3::read();
+    Cr3::write(kernel_level_4_frame(), flags);
+}
+
+// gives every upper half entry of the kernel's table a level 3 table, so that address spaces can copy them once
+// and still see upper half mappings the kernel makes later
+fn fill_upper_half(kernel_table: &mut PageTable, frame_allocator: &mut BitmapFrameAllocator) -> Result<(), AddressSpaceError> {
+    for entry in kernel_table.iter_mut().skip(UPPER_HALF) {
+        if entry.is_unused() {
+            let frame = allocate_table(frame_allocator)?;
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+    Ok(())
+}
+
+// frees the table in `frame` at `level`, with all tables and frames below it
+unsafe fn free_table(frame: PhysFrame, level: u8, frame_allocator: &mut BitmapFrameAllocator) {
+    for entry in table_mut(frame).iter() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == 1 {
+            // not entry.frame(), the huge page bit means something else in level 1 entries
+            let frame = PhysFrame::containing_address(entry.addr());
+            if cow::release(frame) {
+                frame_allocator.deallocate_frame(frame);
+            }
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if level == 2 {
+                use super::HugeFrameAllocator;
+                frame_allocator.deallocate_huge_frame(PhysFrame::<Size2MiB>::containing_address(entry.addr()));
+            }
+            // 1 GiB pages map physical memory that isn't ours, see huge_page::map_gigantic_page
+        } else {
+            free_table(entry.frame().unwrap(), level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+    report::count_freed(Purpose::PageTables, 1);
+}
+
+// what a page may do is decided by its own entry, the tables above it allow everything
+fn table_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+}
+
+// calls f with every 4 KiB page mapped below the table in `frame` at `level`, `base` is the address the table starts at
+unsafe fn for_each_page<E>(
+    frame: PhysFrame,
+    level: u8,
+    base: u64,
+    f: &mut dyn FnMut(Page, &mut PageTableEntry) -> Result<(), E>,
+) -> Result<(), E> {
+    let shift = 12 + 9 * (level as u64 - 1);
+    for (index, entry) in table_mut(frame).iter_mut().enumerate() {
+        let flags = entry.flags();
+        let addr = base | ((index as u64) << shift);
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        } else if level == 1 {
+            f(Page::containing_address(VirtAddr::new(addr)), entry)?;
+        } else if !flags.contains(PageTableFlags::HUGE_PAGE) {
+            for_each_page(entry.frame().unwrap(), level - 1, addr, f)?;
+        }
+    }
+    Ok(())
+}
+
+fn allocate_zeroed_frame(frame_allocator: &mut BitmapFrameAllocator) -> Result<PhysFrame, AddressSpaceError> {
+    let frame = frame_allocator.allocate_frame().ok_or(AddressSpaceError::FrameAllocationFailed)?;
+    unsafe { ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096) };
+    Ok(frame)
+}
+
+// a zeroed frame for a page table, counted in memory::report
+fn allocate_table(frame_allocator: &mut BitmapFrameAllocator) -> Result<PhysFrame, AddressSpaceError> {
+    let frame = allocate_zeroed_frame(frame_allocator)?;
+    report::count_allocated(Purpose::PageTables, 1);
+    Ok(frame)
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
@@ -0,0 +1,187 @@
+use super::{phys_to_virt, with_kernel_paging};
+use core::ptr;
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{page_table::PageTableEntry, FrameAllocator, PageTable, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+/// Marks read-only entries whose frame is shared copy-on-write, the first write gets the page a copy of its own
+/// (or just write access back, if nobody else uses the frame anymore).
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// How many frames can be shared at the same time, see `AddressSpace::clone_cow`.
+pub const MAX_SHARED_FRAMES: usize = 1 << SLOT_BITS;
+const SLOT_BITS: u32 = 12;
+
+const FRAME_SIZE: u64 = 4096;
+
+// reference counts of the frames mapped more than once, every other frame has a single owner
+static REF_COUNTS: Mutex<RefCounts> = Mutex::new(RefCounts::new());
+
+/// How many page table entries point at `frame`, through `AddressSpace::clone_cow`.
+///
+/// 1 for frames that aren't shared, including ones that aren't mapped at all.
+pub fn ref_count(frame: PhysFrame) -> u32 {
+    REF_COUNTS.lock().count(frame)
+}
+
+// one more entry points at frame, false if too many frames are shared already
+pub(super) fn share(frame: PhysFrame) -> bool {
+    REF_COUNTS.lock().share(frame)
+}
+
+// one entry less points at frame, true if that was the last one and the frame should be freed
+pub(super) fn release(frame: PhysFrame) -> bool {
+    REF_COUNTS.lock().release(frame)
+}
+
+/// Resolves a write to a copy-on-write page of the active address space, called by the page fault handler.
+///
+/// Returns false for every other fault, and if the reference counts or the frame allocator are locked further up
+/// the stack (it runs in the page fault handler).
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE) {
+        return false;
+    }
+    let entry = match unsafe { leaf_entry(Cr3::read().0, addr) } {
+        Some(entry) if entry.flags().contains(COW) => entry,
+        _ => return false,
+    };
+    let mut ref_counts = match REF_COUNTS.try_lock() {
+        Some(ref_counts) => ref_counts,
+        None => return false,
+    };
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    let flags = (entry.flags() - COW) | PageTableFlags::WRITABLE;
+    if ref_counts.count(frame) > 1 {
+        let copy = match with_kernel_paging(|_, frame_allocator| frame_allocator.allocate_frame()).flatten() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        unsafe {
+            let from = phys_to_virt(frame.start_address()).as_ptr::<u8>();
+            ptr::copy_nonoverlapping(from, phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(), FRAME_SIZE as usize);
+        }
+        ref_counts.release(frame);
+        entry.set_frame(copy, flags);
+    } else {
+        // everyone else already made a copy
+        entry.set_flags(flags);
+    }
+    tlb::flush(addr);
+    true
+}
+
+// level 1 entry mapping addr, None if it isn't mapped by a 4 KiB page
+unsafe fn leaf_entry(level_4_frame: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table: &'static mut PageTable = &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr();
+    for (level, &index) in indexes.iter().enumerate() {
+        let entry = &mut table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if level == 3 {
+            return Some(entry);
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = &mut *phys_to_virt(entry.addr()).as_mut_ptr();
+    }
+    None
+}
+
+// hash table from frame number to reference count, with linear probing. Only frames with 2 or more references
+// are in it, and one slot always stays empty so that lookups end.
+struct RefCounts {
+    slots: [Option<(u64, u32)>; MAX_SHARED_FRAMES],
+    len: usize,
+}
+
+impl RefCounts {
+    const fn new() -> Self {
+        RefCounts {
+            slots: [None; MAX_SHARED_FRAMES],
+            len: 0,
+        }
+    }
+
+    fn count(&self, frame: PhysFrame) -> u32 {
+        match self.find(frame_number(frame)) {
+            Ok(slot) => self.slots[slot].unwrap().1,
+            Err(_) => 1,
+        }
+    }
+
+    fn share(&mut self, frame: PhysFrame) -> bool {
+        let number = frame_number(frame);
+        match self.find(number) {
+            Ok(slot) => self.slots[slot].as_mut().unwrap().1 += 1,
+            Err(_) if self.len + 1 == MAX_SHARED_FRAMES => return false,
+            Err(slot) => {
+                self.slots[slot] = Some((number, 2));
+                self.len += 1;
+            }
+        }
+        true
+    }
+
+    fn release(&mut self, frame: PhysFrame) -> bool {
+        let slot = match self.find(frame_number(frame)) {
+            Ok(slot) => slot,
+            Err(_) => return true,
+        };
+        let count = &mut self.slots[slot].as_mut().unwrap().1;
+        *count -= 1;
+        if *count == 1 {
+            self.remove(slot);
+        }
+        false
+    }
+
+    // Ok with the slot holding `number`, or Err with the empty slot where it would go
+    fn find(&self, number: u64) -> Result<usize, usize> {
+        let mut slot = home_slot(number);
+        loop {
+            match self.slots[slot] {
+                Some((other, _)) if other == number => return Ok(slot),
+                Some(_) => slot = (slot + 1) % MAX_SHARED_FRAMES,
+                None => return Err(slot),
+            }
+        }
+    }
+
+    // empties slot, and moves later entries of the same probe run up so that they can still be found
+    fn remove(&mut self, slot: usize) {
+        let mut hole = slot;
+        self.slots[hole] = None;
+        let mut next = (hole + 1) % MAX_SHARED_FRAMES;
+        while let Some((number, _)) = self.slots[next] {
+            // distance from the entry's home slot, it can move into the hole if that's not past its home
+            let home = home_slot(number);
+            let distance = (next + MAX_SHARED_FRAMES - home) % MAX_SHARED_FRAMES;
+            let distance_to_hole = (next + MAX_SHARED_FRAMES - hole) % MAX_SHARED_FRAMES;
+            if distance >= distance_to_hole {
+                self.slots[hole] = self.slots[next].take();
+                hole = next;
+            }
+            next = (next + 1) % MAX_SHARED_FRAMES;
+        }
+        self.len -= 1;
+    }
+}
+
+fn frame_number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / FRAME_SIZE
+}
+
+// fibonacci hashing, spreads out the runs of neighbouring frames that get shared together
+fn home_slot(number: u64) -> usize {
+    (number.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - SLOT_BITS)) as usize
+}
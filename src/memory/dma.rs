@@ -0,0 +1,62 @@
+use super::report::{self, Purpose};
+use super::{phys_to_virt, with_kernel_paging, Zone};
+use core::{ptr, slice};
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Physically contiguous, zeroed memory for devices to read and write, e.g. a virtio ring or an AHCI command list.
+/// Its frames go back to the frame allocator when it's dropped.
+///
+/// The kernel accesses it through the physical memory mapping, which is cached. That's fine for DMA on x86,
+/// where device accesses are cache coherent.
+pub struct DmaBuffer {
+    first: PhysFrame,
+    frames: usize,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates `len` bytes (rounded up to whole frames) in `zone`, starting at a multiple of `align` bytes.
+    ///
+    /// None if there's no such run of free frames, or `memory::init_kernel_paging` wasn't called yet.
+    pub fn new(len: usize, align: u64, zone: Zone) -> Option<Self> {
+        let frames = ((len as u64 + FRAME_SIZE - 1) / FRAME_SIZE).max(1) as usize;
+        let first = with_kernel_paging(|_, frame_allocator| frame_allocator.allocate_contiguous(frames, align, zone))??;
+        report::count_allocated(Purpose::Dma, frames as u64);
+        let buffer = DmaBuffer { first, frames, len };
+        unsafe { ptr::write_bytes(buffer.virt_addr().as_mut_ptr::<u8>(), 0, frames * FRAME_SIZE as usize) };
+        Some(buffer)
+    }
+
+    /// Address to hand to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.first.start_address()
+    }
+
+    /// Address the kernel accesses the buffer at.
+    pub fn virt_addr(&self) -> VirtAddr {
+        phys_to_virt(self.phys_addr())
+    }
+
+    /// Size in bytes, as requested.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        with_kernel_paging(|_, frame_allocator| unsafe { frame_allocator.deallocate_contiguous(self.first, self.frames) })
+            .expect("kernel paging not initialized");
+        report::count_freed(Purpose::Dma, self.frames as u64);
+    }
+}
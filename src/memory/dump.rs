@@ -0,0 +1,161 @@
+use super::phys_to_virt;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// Virtually and physically contiguous pages with the same flags, merged by `walk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub virt_start: VirtAddr,
+    pub phys_start: PhysAddr,
+    /// in bytes
+    pub size: u64,
+    /// Flags that are in effect for the range: writable and user accessible only if every level of the walk says so,
+    /// no-execute if any level does. `HUGE_PAGE` is set for 2 MiB and 1 GiB pages.
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.virt_start && addr - self.virt_start < self.size
+    }
+
+    /// Physical address `addr` is mapped to, if it's in the range.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        if self.contains(addr) {
+            Some(self.phys_start + (addr - self.virt_start))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:#018x}..{:#018x} -> {:#x} {}{}{}{}{} ({} KiB)",
+            self.virt_start.as_u64(),
+            self.virt_start.as_u64().wrapping_add(self.size),
+            self.phys_start.as_u64(),
+            flag(PageTableFlags::PRESENT, 'P'),
+            flag(PageTableFlags::WRITABLE, 'W'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'U'),
+            flag(PageTableFlags::NO_EXECUTE, 'N'),
+            flag(PageTableFlags::HUGE_PAGE, 'H'),
+            self.size / 1024
+        )
+    }
+}
+
+/// Level 4 table the CPU is using right now.
+pub fn active_level_4_frame() -> PhysFrame {
+    Cr3::read().0
+}
+
+/// Walks all four levels of the page tables below `level_4_frame` and calls `f` for every mapped range, in order of
+/// virtual address. Reads the tables through the physical memory mapping, so `memory::init` has to be called first.
+///
+/// Doesn't allocate, so it also works before the heap is set up or from fault handlers.
+pub fn walk(level_4_frame: PhysFrame, f: &mut dyn FnMut(MappedRange)) {
+    let mut merger = Merger { current: None, f };
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk_table(level_4_frame.start_address(), 4, 0, parent_flags, &mut merger);
+    if let Some(range) = merger.current {
+        (merger.f)(range);
+    }
+}
+
+/// The mapped ranges below `level_4_frame` as a list, see `walk`.
+pub fn ranges(level_4_frame: PhysFrame) -> Vec<MappedRange> {
+    let mut ranges = Vec::new();
+    walk(level_4_frame, &mut |range| ranges.push(range));
+    ranges
+}
+
+/// Prints the mapped ranges below `level_4_frame` on VGA and serial, one per line.
+pub fn print(level_4_frame: PhysFrame) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = write_ranges(&mut *crate::vga_buffer::WRITER.lock(), level_4_frame);
+        let _ = write_ranges(&mut *crate::serial::SERIAL1.lock(), level_4_frame);
+    });
+}
+
+/// `print` for the page tables in CR3.
+pub fn print_active() {
+    print(active_level_4_frame());
+}
+
+fn write_ranges(out: &mut impl Write, level_4_frame: PhysFrame) -> fmt::Result {
+    writeln!(out, "page tables at {:#x} (P present, W writable, U user, N no-execute, H huge):", level_4_frame.start_address().as_u64())?;
+    let mut result = Ok(());
+    walk(level_4_frame, &mut |range| result = result.and_then(|_| writeln!(out, "{}", range)));
+    result
+}
+
+// joins each range with the previous one if it continues it
+struct Merger<'a> {
+    current: Option<MappedRange>,
+    f: &'a mut dyn FnMut(MappedRange),
+}
+
+impl Merger<'_> {
+    fn push(&mut self, range: MappedRange) {
+        if let Some(current) = &mut self.current {
+            // wrapping: the last page of the address space ends at 2^64
+            let continues = current.virt_start.as_u64().wrapping_add(current.size) == range.virt_start.as_u64()
+                && current.phys_start + current.size == range.phys_start
+                && current.flags == range.flags;
+            if continues {
+                current.size += range.size;
+                return;
+            }
+        }
+        if let Some(previous) = self.current.replace(range) {
+            (self.f)(previous);
+        }
+    }
+}
+
+fn walk_table(table: PhysAddr, level: u8, base: u64, parent_flags: PageTableFlags, merger: &mut Merger) {
+    let table: &PageTable = unsafe { &*phys_to_virt(table).as_ptr() };
+    let shift = 12 + 9 * (level as u64 - 1);
+    for (index, entry) in table.iter().enumerate() {
+        let entry_flags = entry.flags();
+        if !entry_flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let addr = base | ((index as u64) << shift);
+        let flags = effective_flags(parent_flags, entry_flags);
+        // the huge page bit is PAT in level 1 entries, and reserved in level 4 ones
+        let huge = entry_flags.contains(PageTableFlags::HUGE_PAGE) && (level == 2 || level == 3);
+        if level == 1 || huge {
+            let flags = if huge { flags } else { flags - PageTableFlags::HUGE_PAGE };
+            merger.push(MappedRange {
+                virt_start: canonical(addr),
+                phys_start: entry.addr(),
+                size: 1 << shift,
+                flags,
+            });
+        } else {
+            walk_table(entry.addr(), level - 1, addr, flags, merger);
+        }
+    }
+}
+
+// writable and user accessible only if every level says so, no-execute if any level does
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let all_levels = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let changing = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+    (entry - all_levels - changing) | (entry & parent & all_levels) | (parent & PageTableFlags::NO_EXECUTE)
+}
+
+// sign extends bit 47, addresses in the upper half of the L4 table start at 0xffff_8000_0000_0000
+fn canonical(addr: u64) -> VirtAddr {
+    VirtAddr::new(((addr << 16) as i64 >> 16) as u64)
+}
@@ -0,0 +1,411 @@
+use super::huge_page::HugeFrameAllocator;
+use crate::allocator::buddy::{self, BuddyAllocator};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = 64;
+// a 2 MiB frame is 512 frames, which are 8 whole bitmap words
+const WORDS_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize / BITS_PER_WORD;
+// bitmap words covering the ISA DMA zone, which single frames only come from once everything above is used
+const ISA_DMA_WORDS: usize = (16 << 20) / FRAME_SIZE as usize / BITS_PER_WORD;
+
+/// Physical address ranges to allocate frames from, for devices that can't reach all of memory.
+///
+/// The zones nest, each one also contains the ones below it. Allocations try the part of the zone above
+/// the next smaller zone first, so that memory only some devices can use is taken last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, for the legacy ISA DMA controller.
+    IsaDma,
+    /// Below 4 GiB, for devices with 32-bit DMA addresses.
+    Dma32,
+    /// Anywhere.
+    Normal,
+}
+
+impl Zone {
+    /// First physical address past the zone.
+    pub fn end(self) -> u64 {
+        match self {
+            Zone::IsaDma => 16 << 20,
+            Zone::Dma32 => 4 << 30,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    /// Where allocations from the zone start looking, the end of the next smaller zone.
+    pub fn preferred_start(self) -> u64 {
+        self.smaller().map_or(0, Zone::end)
+    }
+
+    fn smaller(self) -> Option<Zone> {
+        match self {
+            Zone::IsaDma => None,
+            Zone::Dma32 => Some(Zone::IsaDma),
+            Zone::Normal => Some(Zone::Dma32),
+        }
+    }
+}
+
+/// Frame allocator keeping one bit per physical frame, set when the frame is in use.
+///
+/// The bitmap covers everything from address 0 up to the end of the last usable region,
+/// frames which aren't `Usable` in the bootloader's memory map are marked as used from the start
+/// and never handed out. The bitmap itself lives in the first usable region large enough for it.
+pub struct BitmapFrameAllocator {
+    memory_map: &'static MemoryMap,
+    bitmap: &'static mut [u64],
+    frame_count: usize, // frames covered by the bitmap
+    usable_frames: usize,
+    free_frames: usize,
+    next_word: usize, // no free frames below this bitmap word
+    next_word_above_isa: usize, // same, but starting at ISA_DMA_WORDS, where allocate_frame looks first
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the bitmap from the bootloader's memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed memory map is valid
+    /// (all frames marked `Usable` are really unused), and that the complete physical memory is mapped
+    /// at `physical_memory_offset`. Also, it must be called only once.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let memory_end = usable_regions().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frame_count = (memory_end / FRAME_SIZE) as usize;
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_size = (words * 8) as u64;
+
+        // first usable region with room for the bitmap, the bitmap is kept at its start
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size)
+            .expect("no usable memory region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+
+        let mut allocator = BitmapFrameAllocator {
+            memory_map,
+            bitmap,
+            frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+            next_word_above_isa: ISA_DMA_WORDS,
+        };
+
+        // everything starts as used, then usable regions are released frame by frame
+        for word in allocator.bitmap.iter_mut() {
+            *word = u64::MAX;
+        }
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for frame in start..end {
+                allocator.set_free(frame);
+            }
+            allocator.usable_frames += end - start;
+        }
+
+        // frames holding the bitmap are taken for good
+        let bitmap_frames = ((bitmap_size + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        let first_bitmap_frame = (bitmap_start / FRAME_SIZE) as usize;
+        for frame in first_bitmap_frame..first_bitmap_frame + bitmap_frames {
+            allocator.set_used(frame);
+        }
+        allocator.next_word = 0;
+        allocator.next_word_above_isa = ISA_DMA_WORDS;
+
+        allocator
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of usable frames currently allocated, including the ones holding the bitmap.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Number of frames marked `Usable` in the memory map.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Whether any of `start..end` is `Usable` in the memory map, i.e. RAM this allocator hands out.
+    pub fn overlaps_usable(&self, start: PhysAddr, end: PhysAddr) -> bool {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .any(|r| r.range.start_addr() < end.as_u64() && start.as_u64() < r.range.end_addr())
+    }
+
+    /// Allocates `count` physically contiguous frames that all lie in `zone`, the first one aligned to `align`
+    /// bytes (a power of two, anything below 4 KiB means 4 KiB).
+    ///
+    /// The frames come from above `zone.preferred_start()` if possible, otherwise from further down in the zone.
+    /// Searches the bitmap frame by frame, so it's slower than `allocate_frame`. None if `count` is 0.
+    pub fn allocate_contiguous(&mut self, count: usize, align: u64, zone: Zone) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "alignment {:#x} isn't a power of two", align);
+        if count == 0 {
+            return None;
+        }
+        let align = (align / FRAME_SIZE).max(1) as usize;
+        let end = self.frame_count.min((zone.end() / FRAME_SIZE) as usize);
+        // each smaller zone in turn, only looking at runs that start below where the last search started
+        let mut search_end = end;
+        let mut search_zone = Some(zone);
+        while let Some(current) = search_zone {
+            let start = (current.preferred_start() / FRAME_SIZE) as usize;
+            if let Some(first) = self.find_free_run(start, search_end, end, count, align) {
+                for frame in first..first + count {
+                    self.set_used(frame);
+                }
+                return Some(PhysFrame::containing_address(PhysAddr::new(first as u64 * FRAME_SIZE)));
+            }
+            search_end = start;
+            search_zone = current.smaller();
+        }
+        None
+    }
+
+    // first run of count free frames starting in start..starts_end and ending before end, aligned to align frames
+    fn find_free_run(&self, start: usize, starts_end: usize, end: usize, count: usize, align: usize) -> Option<usize> {
+        // there are no free frames below next_word
+        let mut first = align_up(start.max(self.next_word * BITS_PER_WORD), align);
+        while first < starts_end && first + count <= end {
+            match (first..first + count).find(|&frame| self.is_used(frame)) {
+                Some(used) => first = align_up(used + 1, align),
+                None => return Some(first),
+            }
+        }
+        None
+    }
+
+    /// Frees frames returned by `allocate_contiguous`.
+    ///
+    /// Unsafe because the frames must not be used anymore. Panics like `deallocate_frame` if one of them isn't allocated.
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame, count: usize) {
+        for frame in PhysFrame::range(first, first + count as u64) {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    // first 2 MiB aligned group of free words in start..end, there are no free frames (so no free 2 MiB frames either)
+    // below the next_word hints that get passed as start
+    fn find_free_huge_frame(&self, start: usize, end: usize) -> Option<usize> {
+        (align_up(start, WORDS_PER_HUGE_FRAME)..end)
+            .step_by(WORDS_PER_HUGE_FRAME)
+            .find(|&w| self.bitmap.get(w..w + WORDS_PER_HUGE_FRAME).map_or(false, |words| words.iter().all(|&word| word == 0)))
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        debug_assert!(!self.is_used(frame));
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        debug_assert!(self.is_used(frame));
+        self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+        self.free_frames += 1;
+        self.next_word = self.next_word.min(frame / BITS_PER_WORD);
+        if frame / BITS_PER_WORD >= ISA_DMA_WORDS {
+            self.next_word_above_isa = self.next_word_above_isa.min(frame / BITS_PER_WORD);
+        }
+    }
+}
+
+fn align_up(frame: usize, align: usize) -> usize {
+    (frame + align - 1) / align * align
+}
+
+fn frame_number(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // skip over words with all 64 frames in use, frames below 16 MiB are kept for ISA DMA as long as possible
+        let bitmap = &self.bitmap;
+        let is_free = |w: &usize| bitmap[*w] != u64::MAX;
+        let word = match (self.next_word_above_isa..bitmap.len()).find(is_free) {
+            Some(word) => {
+                self.next_word_above_isa = word;
+                word
+            }
+            None => {
+                self.next_word_above_isa = bitmap.len();
+                let word = (self.next_word..ISA_DMA_WORDS.min(bitmap.len())).find(is_free)?;
+                self.next_word = word;
+                word
+            }
+        };
+
+        let frame = word * BITS_PER_WORD + self.bitmap[word].trailing_ones() as usize;
+        // the last word may have bits past the end of memory, those are set but be careful anyway
+        if frame >= self.frame_count {
+            return None;
+        }
+        self.set_used(frame);
+        Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Gives the frame back to the allocator.
+    ///
+    /// Panics if the frame isn't currently allocated, which catches double frees. Passing a frame
+    /// this allocator didn't hand out (e.g. one the bootloader reserved) isn't detected.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let frame = frame_number(frame);
+        assert!(frame < self.frame_count, "frame {:#x} is outside of usable memory", frame);
+        assert!(self.is_used(frame), "frame {:#x} freed twice", frame);
+        self.set_free(frame);
+    }
+}
+
+impl HugeFrameAllocator for BitmapFrameAllocator {
+    fn allocate_huge_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        // like allocate_frame, frames below 16 MiB only once there are none left above
+        let above_isa = self.find_free_huge_frame(self.next_word_above_isa, self.bitmap.len());
+        let word = match above_isa {
+            Some(word) => word,
+            None => self.find_free_huge_frame(self.next_word, ISA_DMA_WORDS.min(self.bitmap.len()))?,
+        };
+
+        for word in &mut self.bitmap[word..word + WORDS_PER_HUGE_FRAME] {
+            *word = u64::MAX;
+        }
+        self.free_frames -= WORDS_PER_HUGE_FRAME * BITS_PER_WORD;
+        let addr = (word * BITS_PER_WORD) as u64 * FRAME_SIZE;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Panics if any frame in the 2 MiB frame isn't currently allocated.
+    unsafe fn deallocate_huge_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let word = (frame.start_address().as_u64() / FRAME_SIZE) as usize / BITS_PER_WORD;
+        assert!(word + WORDS_PER_HUGE_FRAME <= self.bitmap.len(), "frame {:?} is outside of usable memory", frame);
+        for word in &mut self.bitmap[word..word + WORDS_PER_HUGE_FRAME] {
+            assert_eq!(*word, u64::MAX, "2 MiB frame {:?} freed twice", frame);
+            *word = 0;
+        }
+        self.free_frames += WORDS_PER_HUGE_FRAME * BITS_PER_WORD;
+        self.next_word = self.next_word.min(word);
+        if word >= ISA_DMA_WORDS {
+            self.next_word_above_isa = self.next_word_above_isa.min(word);
+        }
+    }
+}
+
+/// Physical frame allocator on top of `BuddyAllocator`, which can also hand out physically contiguous frame ranges.
+pub struct BuddyFrameAllocator {
+    buddy: BuddyAllocator,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator managing all `Usable` regions from the bootloader's memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed memory map is valid,
+    /// that the complete physical memory is mapped at `physical_memory_offset`, and that none of
+    /// the usable frames are handed out by another frame allocator.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let mut allocator = Self::new(physical_memory_offset);
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        for region in usable_regions {
+            let first = PhysFrame::containing_address(PhysAddr::new(region.range.start_addr()));
+            let count = (region.range.end_addr() - region.range.start_addr()) / FRAME_SIZE;
+            allocator.add_frames(first, count as usize);
+        }
+        allocator
+    }
+
+    /// Creates an allocator without any frames, see `add_frames`.
+    pub fn new(physical_memory_offset: VirtAddr) -> Self {
+        let buddy = BuddyAllocator::with_block_size(FRAME_SIZE as usize, physical_memory_offset.as_u64() as usize);
+        BuddyFrameAllocator { buddy }
+    }
+
+    /// Hands `count` frames starting at `first` over to the allocator, e.g. ones taken from another frame allocator.
+    ///
+    /// Unsafe because the frames must be unused, not managed by this allocator already, and the complete
+    /// physical memory must be mapped at the `physical_memory_offset` passed to `new`.
+    pub unsafe fn add_frames(&mut self, first: PhysFrame, count: usize) {
+        self.buddy.add_region(first.start_address().as_u64() as usize, count * FRAME_SIZE as usize);
+    }
+
+    /// Allocates `count` physically contiguous frames, rounded up to the next power of two.
+    ///
+    /// The first frame is aligned to the rounded up size.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let order = Self::order(count)?;
+        let addr = self.buddy.allocate_block(order)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr as u64)))
+    }
+
+    /// Frees frames returned by `allocate_contiguous`, `count` must be the same as when allocating.
+    ///
+    /// Unsafe because the frames must not be used anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame, count: usize) {
+        let order = Self::order(count).expect("freeing more frames than any order");
+        self.buddy.free_block(first.start_address().as_u64() as usize, order);
+    }
+
+    /// Number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.buddy.free_bytes() / FRAME_SIZE as usize
+    }
+
+    fn order(count: usize) -> Option<usize> {
+        let order = count.max(1).next_power_of_two().trailing_zeros() as usize;
+        if order < buddy::ORDERS {
+            Some(order)
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(1)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1)
+    }
+}
+
+// blocks are aligned to their size, so 512 contiguous frames are a 2 MiB frame
+const FRAMES_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+impl HugeFrameAllocator for BuddyFrameAllocator {
+    fn allocate_huge_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let first = self.allocate_contiguous(FRAMES_PER_HUGE_FRAME)?;
+        Some(PhysFrame::containing_address(first.start_address()))
+    }
+
+    unsafe fn deallocate_huge_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let first = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(first, FRAMES_PER_HUGE_FRAME)
+    }
+}
@@ -0,0 +1,197 @@
+use super::report::PageTableFrames;
+use super::vma::{self, Backing, VmaError};
+use super::with_kernel_paging;
+use core::slice;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, Translate, TranslateResult, UnmapError},
+        FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Frame allocators that can also hand out physically contiguous, 2 MiB aligned frames for huge pages.
+///
+/// A separate trait instead of `FrameAllocator<Size2MiB>`: with both impls every `allocate_frame()`
+/// call on the allocator would need the frame size spelled out.
+pub trait HugeFrameAllocator: FrameAllocator<Size4KiB> {
+    fn allocate_huge_frame(&mut self) -> Option<PhysFrame<Size2MiB>>;
+
+    /// Unsafe because the frame must have come from `allocate_huge_frame`, and not be used anymore.
+    unsafe fn deallocate_huge_frame(&mut self, frame: PhysFrame<Size2MiB>);
+}
+
+/// Where a virtual address is mapped to, and by what size of page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys_addr: PhysAddr,
+    /// 4 KiB, 2 MiB or 1 GiB
+    pub page_size: u64,
+    pub flags: PageTableFlags,
+}
+
+/// Translates `addr` through the page tables, including huge pages (e.g. the bootloader maps physical memory with 2 MiB pages).
+pub fn translate(mapper: &impl Translate, addr: VirtAddr) -> Option<Translation> {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, offset, flags } => {
+            let (start, page_size) = match frame {
+                MappedFrame::Size4KiB(frame) => (frame.start_address(), Size4KiB::SIZE),
+                MappedFrame::Size2MiB(frame) => (frame.start_address(), Size2MiB::SIZE),
+                MappedFrame::Size1GiB(frame) => (frame.start_address(), Size1GiB::SIZE),
+            };
+            Some(Translation {
+                phys_addr: start + offset,
+                page_size,
+                flags,
+            })
+        }
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+    }
+}
+
+/// Maps `count` 2 MiB pages starting at `start`, each to a new frame from `frame_allocator`.
+///
+/// On error the pages mapped so far are unmapped again. Page tables needed on the way are 4 KiB frames from the same allocator.
+pub fn map_huge_pages(
+    mapper: &mut impl Mapper<Size2MiB>,
+    start: Page<Size2MiB>,
+    count: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl HugeFrameAllocator,
+) -> Result<(), MapToError<Size2MiB>> {
+    for page in Page::range(start, start + count) {
+        let result = match frame_allocator.allocate_huge_frame() {
+            // the mapper sets the HUGE_PAGE flag itself
+            Some(frame) => unsafe { mapper.map_to(page, frame, flags, &mut PageTableFrames(&mut *frame_allocator)) }.map_err(|err| {
+                unsafe { frame_allocator.deallocate_huge_frame(frame) };
+                err
+            }),
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                // nobody has seen the pages mapped so far yet
+                let mapped = page - start;
+                unsafe { unmap_huge_pages(mapper, start, mapped, frame_allocator) }
+                    .expect("huge page mapped just now is gone");
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps `count` 2 MiB pages starting at `start` and gives their frames back to `frame_allocator`.
+///
+/// Unsafe because nothing may use the pages anymore, and their frames must have come from `frame_allocator`.
+pub unsafe fn unmap_huge_pages(
+    mapper: &mut impl Mapper<Size2MiB>,
+    start: Page<Size2MiB>,
+    count: u64,
+    frame_allocator: &mut impl HugeFrameAllocator,
+) -> Result<(), UnmapError> {
+    for page in Page::range(start, start + count) {
+        let frame = unmap_page(mapper, page)?;
+        frame_allocator.deallocate_huge_frame(frame);
+    }
+    Ok(())
+}
+
+/// Maps a single 1 GiB page to `frame`, e.g. for large physical ranges.
+///
+/// Panics if the CPU doesn't support 1 GiB pages, see `gigantic_pages_supported`.
+/// Unsafe for the same reasons as `Mapper::map_to`.
+pub unsafe fn map_gigantic_page(
+    mapper: &mut impl Mapper<Size1GiB>,
+    page: Page<Size1GiB>,
+    frame: PhysFrame<Size1GiB>,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size1GiB>> {
+    assert!(gigantic_pages_supported(), "1 GiB pages aren't supported by this CPU");
+    mapper.map_to(page, frame, flags, &mut PageTableFrames(frame_allocator))?.flush();
+    Ok(())
+}
+
+/// Unmaps a page of any size, flushes it from the TLB and returns the frame it was mapped to.
+///
+/// Unsafe because nothing may use the page anymore.
+pub unsafe fn unmap_page<S: PageSize>(mapper: &mut impl Mapper<S>, page: Page<S>) -> Result<PhysFrame<S>, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+/// Whether the CPU can map 1 GiB pages (CPUID.80000001h:EDX.Page1GB).
+pub fn gigantic_pages_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0 }
+}
+
+/// Memory mapped with 2 MiB pages, for large buffers that are accessed all over and would otherwise take up a
+/// TLB entry per 4 KiB. Its range comes from the kernel `vma` manager. Unmapped, and its frames and range freed,
+/// when dropped.
+pub struct HugePageBuffer {
+    start: Page<Size2MiB>,
+    count: u64,
+}
+
+/// Why a `HugePageBuffer` couldn't be created.
+#[derive(Debug)]
+pub enum HugePageBufferError {
+    /// no 2 MiB aligned range of the kernel address space was free
+    Reserve(VmaError),
+    /// `FrameAllocationFailed` if there aren't enough free 2 MiB frames, or `memory::init_kernel_paging`
+    /// wasn't called yet
+    Map(MapToError<Size2MiB>),
+}
+
+impl HugePageBuffer {
+    /// Reserves a 2 MiB aligned range of `count` 2 MiB pages and maps it with the kernel page table.
+    ///
+    /// Nothing stays mapped or reserved on failure.
+    pub fn new(count: u64) -> Result<Self, HugePageBufferError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        // anonymous so that nothing else maps into it, the huge pages are mapped here and not by `vma::map`
+        let vma = vma::reserve(count * Size2MiB::SIZE, Size2MiB::SIZE, "huge page buffer", flags, Backing::Anonymous)
+            .map_err(HugePageBufferError::Reserve)?;
+        let start = Page::containing_address(vma.start);
+        let mapped = with_kernel_paging(|mapper, frame_allocator| map_huge_pages(mapper, start, count, flags, frame_allocator))
+            .unwrap_or(Err(MapToError::FrameAllocationFailed));
+        if let Err(err) = mapped {
+            vma::release(vma.start).expect("huge page buffer range vanished");
+            return Err(HugePageBufferError::Map(err));
+        }
+        Ok(HugePageBuffer { start, count })
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start.start_address()
+    }
+
+    /// Size in bytes.
+    pub fn len(&self) -> usize {
+        (self.count * Size2MiB::SIZE) as usize
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.start().as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.start().as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for HugePageBuffer {
+    fn drop(&mut self) {
+        with_kernel_paging(|mapper, frame_allocator| unsafe {
+            unmap_huge_pages(mapper, self.start, self.count, frame_allocator)
+                .expect("huge page buffer was unmapped behind its back")
+        })
+        .expect("kernel paging not initialized");
+        vma::release(self.start()).expect("huge page buffer range vanished");
+    }
+}
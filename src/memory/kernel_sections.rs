@@ -0,0 +1,125 @@
+use super::huge_page::translate;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{mapper::FlagUpdateError, Mapper, Page, PageSize, PageTableFlags, Size2MiB, Size4KiB, Translate},
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+extern "C" {
+    // set by linker.ld, all of them page aligned
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// A part of the kernel image that gets its own page permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// code
+    Text,
+    /// constants and string literals
+    Rodata,
+    /// statics, including .bss and the boot-time stacks in it
+    Data,
+}
+
+pub const SECTIONS: [Section; 3] = [Section::Text, Section::Rodata, Section::Data];
+
+impl Section {
+    /// Start and end of the section, both page aligned.
+    pub fn range(self) -> (VirtAddr, VirtAddr) {
+        let (start, end) = unsafe {
+            match self {
+                Section::Text => (&__text_start, &__text_end),
+                Section::Rodata => (&__rodata_start, &__rodata_end),
+                Section::Data => (&__data_start, &__data_end),
+            }
+        };
+        (VirtAddr::from_ptr(start), VirtAddr::from_ptr(end))
+    }
+
+    pub fn contains(self, addr: VirtAddr) -> bool {
+        let (start, end) = self.range();
+        addr >= start && addr < end
+    }
+
+    /// Section `addr` is in, None outside of the kernel image.
+    pub fn containing(addr: VirtAddr) -> Option<Section> {
+        SECTIONS.iter().copied().find(|section| section.contains(addr))
+    }
+
+    /// Permissions `protect` gives the section's pages: writable or executable, never both.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            Section::Text => PageTableFlags::PRESENT,
+            Section::Rodata => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            Section::Data => PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        }
+    }
+}
+
+/// Remaps the kernel image W^X: .text read-only, .rodata read-only and no-execute, .data and .bss no-execute.
+/// The bootloader's stack and its mapping of all physical memory at `physical_memory_offset` become no-execute too.
+///
+/// The bootloader maps all of them writable and executable. Needs EFER.NXE, which `crate::init` turns on, and CR0.WP,
+/// for the kernel's own writes to fault (`memory::init` sets it before calling this).
+pub fn protect(
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB> + Translate),
+    physical_memory_offset: VirtAddr,
+) -> Result<(), FlagUpdateError> {
+    for &section in SECTIONS.iter() {
+        let (start, end) = section.range();
+        let mut addr = start;
+        while addr < end {
+            let old_flags = translate(mapper, addr).ok_or(FlagUpdateError::PageNotMapped)?.flags;
+            // keep what the bootloader set apart from the permissions, e.g. GLOBAL
+            let flags = (old_flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE) | section.flags();
+            // flushed all at once below, the old entries are only more permissive
+            unsafe { Mapper::<Size4KiB>::update_flags(mapper, Page::containing_address(addr), flags)?.ignore() };
+            addr += PAGE_SIZE;
+        }
+    }
+
+    let (stack_start, stack_end) = boot_stack(mapper);
+    let mut addr = stack_start;
+    while addr < stack_end {
+        let flags = translate(mapper, addr).ok_or(FlagUpdateError::PageNotMapped)?.flags | PageTableFlags::NO_EXECUTE;
+        unsafe { Mapper::<Size4KiB>::update_flags(mapper, Page::containing_address(addr), flags)?.ignore() };
+        addr += PAGE_SIZE;
+    }
+
+    // all of physical memory, in 2 MiB pages from address 0 up to the end of the memory map
+    let mut addr = physical_memory_offset;
+    while let Some(translation) = translate(mapper, addr).filter(|t| t.page_size == Size2MiB::SIZE) {
+        let flags = translation.flags | PageTableFlags::NO_EXECUTE;
+        unsafe { Mapper::<Size2MiB>::update_flags(mapper, Page::containing_address(addr), flags)?.ignore() };
+        addr += Size2MiB::SIZE;
+    }
+    tlb::flush_all();
+    Ok(())
+}
+
+/// Start and end of the stack the bootloader started the kernel on (the `KernelStack` region of the memory map),
+/// found from the current stack pointer, so this has to be called on that stack.
+///
+/// The bootloader maps it in a level 4 entry of its own, with an unmapped guard page below it.
+pub fn boot_stack(mapper: &impl Translate) -> (VirtAddr, VirtAddr) {
+    let on_stack = 0u8;
+    let is_stack_page = |addr: VirtAddr| {
+        Section::containing(addr).is_none() && matches!(translate(mapper, addr), Some(t) if t.page_size == PAGE_SIZE)
+    };
+    let mut start = VirtAddr::from_ptr(&on_stack).align_down(PAGE_SIZE);
+    while is_stack_page(start - PAGE_SIZE) {
+        start -= PAGE_SIZE;
+    }
+    let mut end = start;
+    while is_stack_page(end) {
+        end += PAGE_SIZE;
+    }
+    (start, end)
+}
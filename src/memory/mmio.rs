@@ -0,0 +1,82 @@
+use super::vma::{self, Backing, VmaError};
+use super::with_kernel_paging;
+use core::mem;
+use core::ptr;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Device registers (or a framebuffer) mapped into the kernel address space, unmapped when dropped.
+///
+/// The pages are uncached (PCD and PWT set, which is UC with the default PAT), and the frames are never given to the
+/// frame allocator, neither when mapping nor when unmapping.
+pub struct MmioRegion {
+    // start of the mapping, page aligned
+    base: VirtAddr,
+    phys_addr: PhysAddr,
+    // offset of phys_addr into its page
+    offset: u64,
+    len: u64,
+}
+
+/// Maps the `len` bytes of device memory starting at `phys_addr`, which doesn't have to be page aligned.
+///
+/// Fails with `VmaError::NotDeviceMemory` if the range overlaps memory marked `Usable` by the bootloader, the frame
+/// allocator could hand those frames out while they're mapped. Needs `memory::init_kernel_paging` to be called first.
+pub fn map_mmio(phys_addr: PhysAddr, len: u64) -> Result<MmioRegion, VmaError> {
+    let overlaps_ram = with_kernel_paging(|_, frame_allocator| frame_allocator.overlaps_usable(phys_addr, phys_addr + len))
+        .ok_or(VmaError::NoKernelPaging)?;
+    if overlaps_ram {
+        return Err(VmaError::NotDeviceMemory(phys_addr));
+    }
+    let first_frame = phys_addr.align_down(PAGE_SIZE);
+    let offset = phys_addr - first_frame;
+    let base = vma::map(offset + len, "mmio", PageTableFlags::WRITABLE, Backing::Mmio(first_frame))?;
+    Ok(MmioRegion {
+        base,
+        phys_addr,
+        offset,
+        len,
+    })
+}
+
+impl MmioRegion {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    /// Virtual address `phys_addr` is mapped at.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base + self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Volatile read of the `T` at byte `offset`, e.g. a `u32` register.
+    ///
+    /// Panics if it doesn't fit into the region or isn't aligned for `T`.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { ptr::read_volatile(self.ptr::<T>(offset)) }
+    }
+
+    /// Volatile write of `value` to byte `offset`, panics like `read`.
+    pub fn write<T: Copy>(&mut self, offset: u64, value: T) {
+        unsafe { ptr::write_volatile(self.ptr::<T>(offset), value) }
+    }
+
+    fn ptr<T>(&self, offset: u64) -> *mut T {
+        let size = mem::size_of::<T>() as u64;
+        assert!(offset + size <= self.len, "mmio access at {:#x} is outside of the region", offset);
+        let addr = self.virt_addr() + offset;
+        assert!(addr.is_aligned(mem::align_of::<T>() as u64), "unaligned mmio access at {:#x}", offset);
+        addr.as_mut_ptr()
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        unsafe { vma::unmap(self.base) }.expect("mmio region was unmapped behind its back");
+    }
+}
@@ -0,0 +1,157 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// What the kernel allocated frames for, `frame_count` has a counter for each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    /// heap pages, 4 KiB or 2 MiB ones
+    Heap,
+    /// page tables created after boot, for kernel mappings and address spaces
+    PageTables,
+    /// `KernelStack`s and other guard-paged regions
+    Stacks,
+    /// `DmaBuffer`s
+    Dma,
+}
+
+pub const PURPOSES: [Purpose; 4] = [Purpose::Heap, Purpose::PageTables, Purpose::Stacks, Purpose::Dma];
+
+// indexed by Purpose
+static ALLOCATED: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+static FREED: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Frames allocated and freed for one purpose since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCount {
+    pub allocated: u64,
+    pub freed: u64,
+}
+
+impl FrameCount {
+    pub fn in_use(&self) -> u64 {
+        self.allocated - self.freed
+    }
+}
+
+pub fn frame_count(purpose: Purpose) -> FrameCount {
+    FrameCount {
+        allocated: ALLOCATED[purpose as usize].load(Ordering::SeqCst),
+        freed: FREED[purpose as usize].load(Ordering::SeqCst),
+    }
+}
+
+pub(crate) fn count_allocated(purpose: Purpose, frames: u64) {
+    ALLOCATED[purpose as usize].fetch_add(frames, Ordering::SeqCst);
+}
+
+pub(crate) fn count_freed(purpose: Purpose, frames: u64) {
+    FREED[purpose as usize].fetch_add(frames, Ordering::SeqCst);
+}
+
+/// Frame allocator for `Mapper::map_to` that counts the frames the mapper takes for new page tables.
+pub(crate) struct PageTableFrames<'a, A>(pub &'a mut A);
+
+unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for PageTableFrames<'_, A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.0.allocate_frame()?;
+        count_allocated(Purpose::PageTables, 1);
+        Some(frame)
+    }
+}
+
+/// Totals of the bootloader's memory map, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BootMemory {
+    /// every region apart from `Reserved` ones (firmware and device memory)
+    pub total: u64,
+    /// what the frame allocator gets
+    pub usable: u64,
+    /// kernel image and boot stack
+    pub kernel: u64,
+    /// the page tables the bootloader set up
+    pub page_tables: u64,
+    /// bootloader code and data, including the boot info
+    pub bootloader: u64,
+}
+
+impl BootMemory {
+    pub fn from_memory_map(memory_map: &MemoryMap) -> Self {
+        let mut memory = BootMemory::default();
+        for region in memory_map.iter() {
+            let size = region.range.end_addr() - region.range.start_addr();
+            match region.region_type {
+                MemoryRegionType::Reserved => continue,
+                MemoryRegionType::Usable => memory.usable += size,
+                MemoryRegionType::Kernel | MemoryRegionType::KernelStack => memory.kernel += size,
+                MemoryRegionType::PageTable => memory.page_tables += size,
+                MemoryRegionType::Bootloader | MemoryRegionType::BootInfo | MemoryRegionType::Package => {
+                    memory.bootloader += size
+                }
+                _ => {}
+            }
+            memory.total += size;
+        }
+        memory
+    }
+}
+
+/// Prints every region of the memory map with its type and size, then the `BootMemory` totals, on VGA and serial.
+pub fn print_boot_memory(memory_map: &MemoryMap) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = write_boot_memory(&mut *crate::vga_buffer::WRITER.lock(), memory_map);
+        let _ = write_boot_memory(&mut *crate::serial::SERIAL1.lock(), memory_map);
+    });
+}
+
+/// Prints the frame counters of all purposes on VGA and serial.
+pub fn print_frame_counts() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = write_frame_counts(&mut *crate::vga_buffer::WRITER.lock());
+        let _ = write_frame_counts(&mut *crate::serial::SERIAL1.lock());
+    });
+}
+
+fn write_boot_memory(out: &mut impl Write, memory_map: &MemoryMap) -> fmt::Result {
+    writeln!(out, "boot memory map:")?;
+    for region in memory_map.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        writeln!(out, "{:#012x}-{:#012x} {:?} {}", start, end, region.region_type, Size(end - start))?;
+    }
+    let memory = BootMemory::from_memory_map(memory_map);
+    writeln!(out, "total {}, usable {}", Size(memory.total), Size(memory.usable))?;
+    writeln!(
+        out,
+        "kernel {}, page tables {}, bootloader {}",
+        Size(memory.kernel),
+        Size(memory.page_tables),
+        Size(memory.bootloader)
+    )
+}
+
+fn write_frame_counts(out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "frames in use (allocated/freed):")?;
+    for &purpose in PURPOSES.iter() {
+        let count = frame_count(purpose);
+        writeln!(out, "{:?}: {} ({}/{})", purpose, Size(count.in_use() * FRAME_SIZE), count.allocated, count.freed)?;
+    }
+    Ok(())
+}
+
+// bytes in the largest unit that keeps them a whole number
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (value, unit) = match self.0 {
+            bytes if bytes != 0 && bytes % (1 << 30) == 0 => (bytes >> 30, "GiB"),
+            bytes if bytes != 0 && bytes % (1 << 20) == 0 => (bytes >> 20, "MiB"),
+            bytes if bytes != 0 && bytes % (1 << 10) == 0 => (bytes >> 10, "KiB"),
+            bytes => (bytes, "B"),
+        };
+        write!(f, "{} {}", value, unit)
+    }
+}
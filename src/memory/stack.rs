@@ -0,0 +1,71 @@
+use super::vma::{self, Backing, VmaError};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// A kernel stack in the kernel address space, with an unmapped guard page right below it.
+///
+/// Overflowing it faults on the guard page instead of overwriting whatever is mapped next to it,
+/// the fault handlers then report "stack overflow in stack <name>". Unmapped when dropped.
+pub struct KernelStack {
+    bottom: VirtAddr,
+    pages: u64,
+    name: &'static str,
+}
+
+impl KernelStack {
+    /// Maps a stack of `pages` pages (and its guard page), `name` is what overflows get reported with.
+    ///
+    /// Needs `memory::init_kernel_paging` to be called first.
+    pub fn new(pages: u64, name: &'static str) -> Result<Self, VmaError> {
+        Self::map(pages, name, Backing::Anonymous)
+    }
+
+    /// Like `new`, but each page only gets its frame when it's first touched, for large stacks that mostly stay unused.
+    ///
+    /// The page fault handler runs on its own IST stack, so touching a new page is fine from anywhere, apart from while
+    /// the kernel regions or page table are locked (`vma::handle_page_fault` can't back the page then).
+    pub fn lazy(pages: u64, name: &'static str) -> Result<Self, VmaError> {
+        Self::map(pages, name, Backing::Lazy)
+    }
+
+    fn map(pages: u64, name: &'static str, backing: Backing) -> Result<Self, VmaError> {
+        let flags = PageTableFlags::WRITABLE;
+        let bottom = vma::map_with_guard(pages * PAGE_SIZE, name, flags, backing)?;
+        Ok(KernelStack { bottom, pages, name })
+    }
+
+    /// Initial stack pointer, stacks grow down from here.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + self.pages * PAGE_SIZE
+    }
+
+    /// Lowest usable address, the guard page is the page below.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn guard_page(&self) -> VirtAddr {
+        self.bottom - PAGE_SIZE
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Keeps the stack mapped forever and returns its top, for stacks the CPU switches to by itself (IST entries).
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unsafe {
+            vma::unmap(self.bottom).expect("kernel stack was unmapped behind its back");
+            vma::unmap(self.guard_page()).expect("guard page of kernel stack is gone");
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use super::report::{self, PageTableFrames, Purpose};
+use super::{phys_to_virt, with_kernel_paging};
+use core::ptr;
+use spin::Mutex;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+/// Most regions a `VmaManager` can hold, it keeps them in a fixed array so that it never allocates
+/// (the heap registers its range with it).
+pub const MAX_VMAS: usize = 64;
+
+/// Range `allocate` hands out kernel virtual addresses from: the upper half, apart from the last
+/// level 4 entry. The bootloader only uses the lower half, for the kernel image, its stack and the physical memory mapping.
+pub const KERNEL_VMA_START: u64 = 0xffff_8000_0000_0000;
+pub const KERNEL_VMA_END: u64 = 0xffff_ff80_0000_0000;
+
+/// What a virtual range is mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// New zeroed frames, given back to the frame allocator when unmapped.
+    Anonymous,
+    /// Like `Anonymous`, but nothing is mapped up front: each page gets its frame when it's first
+    /// touched, from the page fault handler (see `handle_page_fault`).
+    Lazy,
+    /// Never mapped, sits below a stack so that overflowing it faults (see `map_with_guard`).
+    Guard,
+    /// Device registers starting at this physical address, mapped uncached.
+    Mmio(PhysAddr),
+    /// Memory starting at this physical address that isn't ours to free, e.g. a framebuffer.
+    Fixed(PhysAddr),
+}
+
+/// A range of kernel virtual memory and what it's used for.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    /// in bytes, a multiple of the page size
+    pub size: u64,
+    /// shown when listing the regions, e.g. "kernel heap"
+    pub purpose: &'static str,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    /// What the frames it owns count as in `memory::report`, None if they aren't counted.
+    pub counted_as: Option<Purpose>,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    /// Flags the pages get mapped with: `flags` plus PRESENT and NO_EXECUTE (kernel code only runs from .text),
+    /// and no caching for MMIO.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let flags = self.flags | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        match self.backing {
+            Backing::Mmio(_) => flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            Backing::Anonymous | Backing::Lazy | Backing::Guard | Backing::Fixed(_) => flags,
+        }
+    }
+
+    // whether the frames behind the region were allocated for it, and are freed with it
+    fn owns_frames(&self) -> bool {
+        self.backing == Backing::Anonymous || self.backing == Backing::Lazy
+    }
+
+    // whether all pages get mapped by `map`, instead of on demand or never
+    fn mapped_up_front(&self) -> bool {
+        self.backing != Backing::Lazy && self.backing != Backing::Guard
+    }
+
+    fn page(&self, index: u64) -> Page {
+        Page::containing_address(self.start + index * PAGE_SIZE)
+    }
+
+    fn pages(&self) -> u64 {
+        self.size / PAGE_SIZE
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    /// no gap in the address space is large enough
+    OutOfAddressSpace,
+    /// the range overlaps the region starting at this address
+    Overlaps(VirtAddr),
+    /// already holding `MAX_VMAS` regions
+    TooManyRegions,
+    /// start, size or physical address isn't page aligned, or the size is 0
+    NotAligned,
+    NoRegion(VirtAddr),
+    MapFailed(MapToError<Size4KiB>),
+    UnmapFailed(UnmapError),
+    /// `map_mmio` was given a range overlapping usable RAM, starting at this address
+    NotDeviceMemory(PhysAddr),
+    /// `memory::init_kernel_paging` wasn't called yet, or the kernel page table is in use further up the stack
+    NoKernelPaging,
+}
+
+/// Keeps track of which virtual ranges are in use, and hands out free ones from its window.
+///
+/// Only bookkeeping, see `map` and `unmap` for the kernel address space with page tables behind it.
+pub struct VmaManager {
+    vmas: [Option<Vma>; MAX_VMAS], // sorted by start address, the first `count` are Some
+    count: usize,
+    window_start: u64,
+    window_end: u64,
+}
+
+impl VmaManager {
+    /// A manager handing out ranges between `window_start` and `window_end`, both page aligned.
+    pub const fn new(window_start: u64, window_end: u64) -> Self {
+        const NONE: Option<Vma> = None;
+        VmaManager {
+            vmas: [NONE; MAX_VMAS],
+            count: 0,
+            window_start,
+            window_end,
+        }
+    }
+
+    /// Finds a free range of `size` bytes aligned to `align` (at least a page) inside the window and records it.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        align: u64,
+        purpose: &'static str,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<Vma, VmaError> {
+        let align = align.max(PAGE_SIZE);
+        if size == 0 || size % PAGE_SIZE != 0 || !align.is_power_of_two() {
+            return Err(VmaError::NotAligned);
+        }
+        // first fit: try the start of the window, then the end of every region in address order
+        let candidates = core::iter::once(self.window_start).chain(self.iter().map(|vma| vma.end().as_u64()));
+        let mut start = None;
+        for candidate in candidates {
+            let candidate = match candidate.checked_add(align - 1) {
+                Some(addr) => addr & !(align - 1),
+                None => break,
+            };
+            let fits = candidate >= self.window_start
+                && candidate.checked_add(size).map_or(false, |end| end <= self.window_end)
+                && self.overlapping(candidate, size).is_none();
+            if fits {
+                start = Some(candidate);
+                break;
+            }
+        }
+        let start = start.ok_or(VmaError::OutOfAddressSpace)?;
+
+        let vma = Vma {
+            start: VirtAddr::new(start),
+            size,
+            purpose,
+            flags,
+            backing,
+            counted_as: None,
+        };
+        self.insert(vma)?;
+        Ok(vma)
+    }
+
+    /// Records a region at a fixed address, which may also be outside of the window (e.g. the heap).
+    pub fn reserve_at(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if vma.size == 0 || !vma.start.is_aligned(PAGE_SIZE) || vma.size % PAGE_SIZE != 0 {
+            return Err(VmaError::NotAligned);
+        }
+        self.insert(vma)
+    }
+
+    /// Forgets the region starting at `start`, and returns it.
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        let index = self.iter().position(|vma| vma.start == start)?;
+        let vma = self.vmas[index].take();
+        // close the gap, the regions have to stay packed at the front
+        for i in index..self.count - 1 {
+            self.vmas[i] = self.vmas[i + 1].take();
+        }
+        self.count -= 1;
+        vma
+    }
+
+    /// The region containing `addr`.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.iter().find(|vma| vma.contains(addr))
+    }
+
+    /// All regions, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas[..self.count].iter().map(|vma| vma.as_ref().unwrap())
+    }
+
+    fn overlapping(&self, start: u64, size: u64) -> Option<&Vma> {
+        let end = start.saturating_add(size);
+        self.iter().find(|vma| vma.start.as_u64() < end && start < vma.end().as_u64())
+    }
+
+    fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if vma.start.as_u64().checked_add(vma.size).is_none() {
+            return Err(VmaError::NotAligned);
+        }
+        if let Some(other) = self.overlapping(vma.start.as_u64(), vma.size) {
+            return Err(VmaError::Overlaps(other.start));
+        }
+        if self.count == MAX_VMAS {
+            return Err(VmaError::TooManyRegions);
+        }
+        let index = self.iter().position(|other| other.start > vma.start).unwrap_or(self.count);
+        for i in (index..self.count).rev() {
+            self.vmas[i + 1] = self.vmas[i].take();
+        }
+        self.vmas[index] = Some(vma);
+        self.count += 1;
+        Ok(())
+    }
+}
+
+static KERNEL_VMAS: Mutex<VmaManager> = Mutex::new(VmaManager::new(KERNEL_VMA_START, KERNEL_VMA_END));
+
+/// Records a region of the kernel address space at a fixed address, without mapping anything.
+///
+/// For ranges mapped by other means (like the heap, which maps its pages as it grows), so that `map` stays clear of them.
+pub fn reserve_at(vma: Vma) -> Result<(), VmaError> {
+    KERNEL_VMAS.lock().reserve_at(vma)
+}
+
+/// Finds a free range of `size` bytes aligned to `align` in the kernel address space and records it, without mapping
+/// anything.
+///
+/// For ranges the caller maps itself (e.g. with huge pages, which `map` can't do), hand it back with `release`.
+pub fn reserve(size: u64, align: u64, purpose: &'static str, flags: PageTableFlags, backing: Backing) -> Result<Vma, VmaError> {
+    KERNEL_VMAS.lock().allocate(size, align, purpose, flags, backing)
+}
+
+/// Forgets the region starting at `start` that `reserve` or `reserve_at` recorded, without unmapping anything.
+pub fn release(start: VirtAddr) -> Result<Vma, VmaError> {
+    KERNEL_VMAS.lock().remove(start).ok_or(VmaError::NoRegion(start))
+}
+
+/// Finds a free range of `size` bytes (rounded up to whole pages) in the kernel address space and maps it,
+/// `Backing::Lazy` and `Backing::Guard` ranges are only reserved.
+///
+/// Nothing stays mapped or reserved on failure.
+pub fn map(size: u64, purpose: &'static str, flags: PageTableFlags, backing: Backing) -> Result<VirtAddr, VmaError> {
+    if let Backing::Mmio(addr) | Backing::Fixed(addr) = backing {
+        if !addr.is_aligned(PAGE_SIZE) {
+            return Err(VmaError::NotAligned);
+        }
+    }
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mut vmas = KERNEL_VMAS.lock();
+    let vma = vmas.allocate(size, PAGE_SIZE, purpose, flags, backing)?;
+    if let Err(err) = map_recorded(&vma) {
+        vmas.remove(vma.start);
+        return Err(err);
+    }
+    Ok(vma.start)
+}
+
+/// Like `map`, with a `Backing::Guard` page right below the range, for stacks.
+///
+/// The guard page is a region of its own with the same purpose, `unmap` it separately. Frames of the range count as
+/// `Purpose::Stacks` in `memory::report`. `Backing::Lazy` stacks work because the page fault handler has its own IST
+/// stack, and doesn't need room on the stack that faulted.
+pub fn map_with_guard(size: u64, purpose: &'static str, flags: PageTableFlags, backing: Backing) -> Result<VirtAddr, VmaError> {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mut vmas = KERNEL_VMAS.lock();
+    if vmas.count + 2 > MAX_VMAS {
+        return Err(VmaError::TooManyRegions);
+    }
+    // one range for both, so that they're next to each other, then split in two
+    let whole = vmas.allocate(size + PAGE_SIZE, PAGE_SIZE, purpose, flags, Backing::Guard)?;
+    vmas.remove(whole.start);
+    let guard = Vma { size: PAGE_SIZE, ..whole };
+    let vma = Vma { start: whole.start + PAGE_SIZE, size, backing, counted_as: Some(Purpose::Stacks), ..whole };
+    vmas.insert(guard)?;
+    vmas.insert(vma)?;
+
+    if let Err(err) = map_recorded(&vma) {
+        vmas.remove(vma.start);
+        vmas.remove(guard.start);
+        return Err(err);
+    }
+    Ok(vma.start)
+}
+
+// maps the pages of a region that was just recorded, if it gets any up front
+fn map_recorded(vma: &Vma) -> Result<(), VmaError> {
+    if !vma.mapped_up_front() {
+        return Ok(());
+    }
+    with_kernel_paging(|mapper, frame_allocator| map_pages(vma, mapper, frame_allocator))
+        .unwrap_or(Err(VmaError::NoKernelPaging))
+}
+
+/// Unmaps the region starting at `start` that `map` returned, and frees its frames if it's anonymous or lazy memory.
+///
+/// Unsafe because nothing may use the region anymore.
+pub unsafe fn unmap(start: VirtAddr) -> Result<Vma, VmaError> {
+    let mut vmas = KERNEL_VMAS.lock();
+    let vma = *vmas.iter().find(|vma| vma.start == start).ok_or(VmaError::NoRegion(start))?;
+    if vma.backing != Backing::Guard {
+        with_kernel_paging(|mapper, frame_allocator| unmap_pages(&vma, vma.pages(), mapper, frame_allocator))
+            .unwrap_or(Err(VmaError::NoKernelPaging))?;
+    }
+    vmas.remove(start);
+    Ok(vma)
+}
+
+/// The kernel region containing `addr`.
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    KERNEL_VMAS.lock().find(addr).copied()
+}
+
+/// Calls `f` for every region of the kernel address space, in address order.
+///
+/// `f` must not map or unmap anything, or touch pages of lazy regions that aren't backed yet: the regions are locked while it runs.
+pub fn for_each(mut f: impl FnMut(&Vma)) {
+    for vma in KERNEL_VMAS.lock().iter() {
+        f(vma);
+    }
+}
+
+/// Purpose of the guard page region containing `addr`, i.e. the name of the stack that overflowed if a fault hit it.
+///
+/// None if `addr` isn't in a guard page, or the regions are locked (it's called from fault handlers).
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let vmas = KERNEL_VMAS.try_lock()?;
+    vmas.find(addr).filter(|vma| vma.backing == Backing::Guard).map(|vma| vma.purpose)
+}
+
+/// Backs the page containing `addr` with a zeroed frame if it's in a `Backing::Lazy` region, called by the page fault handler.
+///
+/// Returns false for faults that can't be fixed that way: addresses outside of lazy regions, writes to read-only ones,
+/// protection violations on pages that are already mapped, and faults while the regions or the kernel page table are locked.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    // try_lock: the fault may have happened with the regions locked further up the stack
+    let vmas = match KERNEL_VMAS.try_lock() {
+        Some(vmas) => vmas,
+        None => return false,
+    };
+    let vma = match vmas.find(addr) {
+        Some(vma) if vma.backing == Backing::Lazy => vma,
+        _ => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !vma.flags.contains(PageTableFlags::WRITABLE) {
+        return false;
+    }
+    let index = (addr - vma.start) / PAGE_SIZE;
+    with_kernel_paging(|mapper, frame_allocator| map_page(vma, index, mapper, frame_allocator).is_ok()).unwrap_or(false)
+}
+
+// maps all pages of vma, on failure the pages mapped so far are unmapped again
+fn map_pages<M, A>(vma: &Vma, mapper: &mut M, frame_allocator: &mut A) -> Result<(), VmaError>
+where
+    M: Mapper<Size4KiB>,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    for index in 0..vma.pages() {
+        if let Err(err) = map_page(vma, index, mapper, frame_allocator) {
+            unsafe { unmap_pages(vma, index, mapper, frame_allocator) }.expect("page mapped just now is gone");
+            return Err(VmaError::MapFailed(err));
+        }
+    }
+    Ok(())
+}
+
+fn map_page<M, A>(vma: &Vma, index: u64, mapper: &mut M, frame_allocator: &mut A) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB>,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let frame = match vma.backing {
+        Backing::Anonymous | Backing::Lazy => {
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            // zeroed through the physical memory mapping, the region itself may not be writable
+            unsafe { ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+            frame
+        }
+        Backing::Mmio(addr) | Backing::Fixed(addr) => PhysFrame::containing_address(addr + index * PAGE_SIZE),
+        Backing::Guard => unreachable!("guard pages are never mapped"),
+    };
+    match unsafe { mapper.map_to(vma.page(index), frame, vma.page_flags(), &mut PageTableFrames(frame_allocator)) } {
+        Ok(flush) => {
+            flush.flush();
+            if let Some(purpose) = vma.counted_as.filter(|_| vma.owns_frames()) {
+                report::count_allocated(purpose, 1);
+            }
+            Ok(())
+        }
+        Err(err) => {
+            if vma.owns_frames() {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            Err(err)
+        }
+    }
+}
+
+// unmaps the first `pages` pages of vma, pages of a lazy region that were never touched are skipped
+unsafe fn unmap_pages<M, A>(vma: &Vma, pages: u64, mapper: &mut M, frame_allocator: &mut A) -> Result<(), VmaError>
+where
+    M: Mapper<Size4KiB>,
+    A: FrameDeallocator<Size4KiB>,
+{
+    for index in 0..pages {
+        let (frame, flush) = match mapper.unmap(vma.page(index)) {
+            Ok(unmapped) => unmapped,
+            Err(UnmapError::PageNotMapped) if vma.backing == Backing::Lazy => continue,
+            Err(err) => return Err(VmaError::UnmapFailed(err)),
+        };
+        flush.flush();
+        if vma.owns_frames() {
+            frame_allocator.deallocate_frame(frame);
+            if let Some(purpose) = vma.counted_as {
+                report::count_freed(purpose, 1);
+            }
+        }
+    }
+    Ok(())
+}
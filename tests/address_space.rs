@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::allocator::HEAP_START;
+use mini_os::memory::address_space::activate_kernel;
+use mini_os::memory::{phys_to_virt, translate, vma, with_kernel_paging, AddressSpace, AddressSpaceError, Backing};
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+// far away from anything the bootloader maps in the lower half
+const USER_ADDR: u64 = 0x7000_0000_0000;
+
+fn user_page() -> Page {
+    Page::containing_address(VirtAddr::new(USER_ADDR))
+}
+
+fn free_frames() -> usize {
+    with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn kernel_mappings_are_shared() {
+    let mut space = AddressSpace::new().unwrap();
+    let heap = VirtAddr::new(HEAP_START as u64);
+    let kernel = with_kernel_paging(|mapper, _| translate(mapper, heap)).unwrap();
+    assert_eq!(space.translate(heap), kernel);
+    assert!(!space.is_private(heap));
+    assert!(space.is_private(VirtAddr::new(USER_ADDR)));
+}
+
+#[test_case]
+fn user_mappings_are_private() {
+    let mut first = AddressSpace::new().unwrap();
+    let mut second = AddressSpace::new().unwrap();
+    first.map_user(user_page(), PageTableFlags::WRITABLE).unwrap();
+
+    let translation = first.translate(VirtAddr::new(USER_ADDR)).unwrap();
+    assert!(translation.flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert!(second.translate(VirtAddr::new(USER_ADDR)).is_none());
+    assert!(with_kernel_paging(|mapper, _| translate(mapper, VirtAddr::new(USER_ADDR))).unwrap().is_none());
+}
+
+#[test_case]
+fn kernel_ranges_cant_be_mapped() {
+    let mut space = AddressSpace::new().unwrap();
+    let heap_page = Page::containing_address(VirtAddr::new(HEAP_START as u64));
+    assert!(matches!(
+        space.map_user(heap_page, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::KernelRange(_))
+    ));
+}
+
+#[test_case]
+fn activated_space_runs_the_kernel() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map_user(user_page(), PageTableFlags::WRITABLE).unwrap();
+    // mapped after the address space was created, in the upper half
+    let shared = vma::map(4096, "shared after creation", PageTableFlags::WRITABLE, Backing::Anonymous).unwrap();
+
+    unsafe {
+        space.activate();
+        assert!(space.is_active());
+        *(USER_ADDR as *mut u64) = 42;
+        *shared.as_mut_ptr::<u64>() = 7;
+        activate_kernel();
+    }
+    assert!(!space.is_active());
+
+    let frame = space.translate(VirtAddr::new(USER_ADDR)).unwrap().phys_addr;
+    assert_eq!(unsafe { *phys_to_virt(frame).as_ptr::<u64>() }, 42);
+    assert_eq!(unsafe { *shared.as_ptr::<u64>() }, 7);
+    unsafe { vma::unmap(shared).unwrap() };
+}
+
+#[test_case]
+fn drop_frees_tables_and_frames() {
+    // the first address space fills in the kernel's upper half tables, those stay
+    drop(AddressSpace::new().unwrap());
+
+    let free_before = free_frames();
+    let mut space = AddressSpace::new().unwrap();
+    for i in 0..3 {
+        space.map_user(user_page() + i, PageTableFlags::WRITABLE).unwrap();
+    }
+    unsafe { space.unmap_user(user_page() + 1).unwrap() };
+    assert!(free_frames() < free_before);
+    unsafe { space.activate() };
+    drop(space);
+    assert_eq!(free_frames(), free_before);
+}
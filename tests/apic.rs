@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+    // QEMU's default machine has an APIC
+    interrupts::init_apic().expect("APIC setup failed");
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::interrupts::{self, apic, with_apic, Controller, InterruptIndex};
+use x86_64::instructions::port::Port;
+
+#[test_case]
+fn apic_is_in_use() {
+    assert!(apic::is_supported());
+    assert_eq!(interrupts::controller(), Controller::Apic);
+}
+
+#[test_case]
+fn legacy_pic_is_masked() {
+    let masks: [u8; 2] = unsafe { [Port::new(0x21).read(), Port::new(0xa1).read()] };
+    assert_eq!(masks, [0xff, 0xff]);
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    let start = interrupts::ticks();
+    // every tick gets an EOI, or the next one would never come
+    while interrupts::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn keyboard_is_the_only_unmasked_irq() {
+    let masked = 1 << 16;
+    with_apic(|apic| {
+        let keyboard = apic.redirection(apic::KEYBOARD_IRQ);
+        assert_eq!(keyboard & 0xff, u64::from(InterruptIndex::Keyboard.as_u8()));
+        assert_eq!(keyboard & masked, 0);
+        assert_eq!(keyboard >> 56, u64::from(apic.id()));
+        for irq in (0..apic.io_apic_inputs()).filter(|&irq| irq != apic::KEYBOARD_IRQ) {
+            assert_ne!(apic.redirection(irq) & masked, 0, "IRQ {} isn't masked", irq);
+        }
+    })
+    .expect("no APIC");
+}
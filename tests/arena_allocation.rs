@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Arena doesn't need the kernel heap, these tests run it over a static buffer
+
+extern crate alloc;
+
+use alloc::{alloc::Layout, boxed::Box, vec::Vec};
+use core::panic::PanicInfo;
+use mini_os::allocator::arena::Arena;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+const ARENA_SIZE: usize = 16 * 1024;
+
+#[repr(align(4096))]
+struct Memory([u8; ARENA_SIZE]);
+
+static mut MEMORY: Memory = Memory([0; ARENA_SIZE]);
+
+// every test gets the whole buffer, tests run one after another
+fn arena() -> Arena {
+    unsafe { Arena::new(MEMORY.0.as_mut_ptr() as usize, ARENA_SIZE) }
+}
+
+#[test_case]
+fn vec_and_box_in_arena() {
+    let arena = arena();
+    let mut vec = Vec::new_in(&arena);
+    for i in 0..500u64 {
+        vec.push(i);
+    }
+    let boxed = Box::new_in([7u8; 100], &arena);
+    assert_eq!(vec.iter().sum::<u64>(), 499 * 500 / 2);
+    assert_eq!(boxed.iter().map(|&b| b as usize).sum::<usize>(), 700);
+    assert!(arena.used() >= 500 * 8 + 100);
+}
+
+#[test_case]
+fn most_recent_allocation_grows_in_place() {
+    let arena = arena();
+    let mut vec: Vec<u64, _> = Vec::with_capacity_in(4, &arena);
+    let start = vec.as_ptr();
+    for i in 0..1000 {
+        vec.push(i);
+    }
+    assert_eq!(vec.as_ptr(), start);
+    assert_eq!(arena.used(), vec.capacity() * 8);
+}
+
+#[test_case]
+fn reset_frees_everything() {
+    let mut arena = arena();
+    let vec: Vec<u8, _> = Vec::with_capacity_in(ARENA_SIZE, &arena);
+    assert!(Box::try_new_in(1u8, &arena).is_err());
+    drop(vec);
+
+    let _first = Box::new_in(1u64, &arena);
+    let _second = Box::new_in(2u64, &arena);
+    drop(_first); // not the most recent allocation, so it stays in use
+    assert_eq!(arena.used(), 16);
+    drop(_second);
+    arena.reset();
+    assert_eq!(arena.used(), 0);
+}
+
+#[test_case]
+fn checkpoints_rewind_later_allocations() {
+    let mut arena = arena();
+    let long_lived = arena.bump(Layout::new::<u64>()) as *mut u64;
+    unsafe { long_lived.write(42) };
+    let checkpoint = arena.checkpoint();
+
+    let parsed = arena.scope(|scratch| {
+        let mut tokens = Vec::new_in(scratch);
+        tokens.extend_from_slice(b"GET /index.html");
+        tokens.iter().filter(|&&b| b == b'/').count()
+    });
+    assert_eq!(parsed, 1);
+    assert_eq!(arena.used(), 8);
+
+    core::mem::forget(Box::new_in([0u8; 256], &arena));
+    assert_eq!(arena.used(), 8 + 256);
+    arena.rewind(checkpoint);
+    assert_eq!(arena.used(), 8);
+    assert_eq!(unsafe { *long_lived }, 42);
+}
@@ -0,0 +1,188 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use mini_os::allocator::{buddy::BuddyAllocator, Locked};
+
+const ARENA_SIZE: usize = 64 * 1024;
+// 16 << 12 == ARENA_SIZE, the whole arena is a single block of this order
+const TOP_ORDER: usize = 12;
+
+// buddy blocks are aligned to their size, so the arena is too. Otherwise it wouldn't be a single top order block
+#[repr(align(65536))]
+struct Arena([u8; ARENA_SIZE]);
+
+// every test gets a fresh allocator over this arena, tests run one after another
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+fn arena_allocator() -> (Locked<BuddyAllocator>, usize) {
+    let allocator = Locked::new(BuddyAllocator::new());
+    let start = unsafe { ARENA.0.as_mut_ptr() as usize };
+    unsafe { allocator.lock().init(start, ARENA_SIZE) };
+    (allocator, start)
+}
+
+#[test_case]
+fn starts_with_one_block() {
+    let (allocator, _) = arena_allocator();
+    let allocator = allocator.lock();
+    assert_eq!(allocator.block_size(TOP_ORDER), ARENA_SIZE);
+    assert_eq!(allocator.free_blocks(TOP_ORDER), 1);
+    assert_eq!(allocator.free_bytes(), ARENA_SIZE);
+}
+
+#[test_case]
+fn small_allocation_splits_every_order() {
+    let (allocator, start) = arena_allocator();
+    let layout = Layout::from_size_align(10, 1).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert_eq!(ptr as usize, start);
+
+    // splitting the top block down to the smallest one leaves one free buddy on every order below the top
+    for order in 0..TOP_ORDER {
+        assert_eq!(allocator.lock().free_blocks(order), 1);
+    }
+    assert_eq!(allocator.lock().free_blocks(TOP_ORDER), 0);
+
+    unsafe { allocator.dealloc(ptr, layout) };
+    assert_eq!(allocator.lock().free_blocks(TOP_ORDER), 1);
+    for order in 0..TOP_ORDER {
+        assert_eq!(allocator.lock().free_blocks(order), 0);
+    }
+}
+
+#[test_case]
+fn buddies_merge_when_both_are_freed() {
+    let (allocator, start) = arena_allocator();
+    let layout = Layout::from_size_align(16, 16).unwrap();
+    let first = unsafe { allocator.alloc(layout) };
+    let second = unsafe { allocator.alloc(layout) };
+    assert_eq!(first as usize, start);
+    assert_eq!(second as usize, start + 16);
+
+    // freeing one half can't merge yet, its buddy is still in use
+    unsafe { allocator.dealloc(first, layout) };
+    assert_eq!(allocator.lock().free_blocks(0), 1);
+    assert_eq!(allocator.lock().free_blocks(TOP_ORDER), 0);
+
+    unsafe { allocator.dealloc(second, layout) };
+    assert_eq!(allocator.lock().free_blocks(0), 0);
+    assert_eq!(allocator.lock().free_blocks(TOP_ORDER), 1);
+}
+
+#[test_case]
+fn sizes_round_up_to_powers_of_two() {
+    let (allocator, _) = arena_allocator();
+    let layout = Layout::from_size_align(3000, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    assert_eq!(allocator.lock().free_bytes(), ARENA_SIZE - 4096);
+    unsafe { allocator.dealloc(ptr, layout) };
+    assert_eq!(allocator.lock().free_bytes(), ARENA_SIZE);
+}
+
+#[test_case]
+fn blocks_are_aligned() {
+    let (allocator, _) = arena_allocator();
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let aligned = Layout::from_size_align(64, 1024).unwrap();
+    let a = unsafe { allocator.alloc(small) };
+    let b = unsafe { allocator.alloc(aligned) };
+    assert_eq!(b as usize % 1024, 0);
+    unsafe {
+        allocator.dealloc(a, small);
+        allocator.dealloc(b, aligned);
+    }
+    assert_eq!(allocator.lock().free_blocks(TOP_ORDER), 1);
+}
+
+#[test_case]
+fn exhausts_and_fully_reclaims() {
+    let (allocator, _) = arena_allocator();
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let mut blocks = [core::ptr::null_mut(); ARENA_SIZE / 1024];
+    for block in blocks.iter_mut() {
+        *block = unsafe { allocator.alloc(layout) };
+        assert!(!block.is_null());
+    }
+    // arena is full, and this allocator isn't the kernel heap, so it can't grow either
+    assert_eq!(allocator.lock().free_bytes(), 0);
+    assert!(unsafe { allocator.alloc(layout) }.is_null());
+
+    // free in an order that keeps buddies apart for as long as possible
+    for block in blocks.iter().step_by(2).chain(blocks.iter().skip(1).step_by(2)) {
+        unsafe { allocator.dealloc(*block, layout) };
+    }
+    assert_eq!(allocator.lock().free_blocks(TOP_ORDER), 1);
+}
+
+#[test_case]
+fn buddy_frame_allocator_hands_out_aligned_contiguous_frames() {
+    use mini_os::memory::{phys_to_virt, HugeFrameAllocator, with_kernel_paging, BuddyFrameAllocator, Zone};
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+    use x86_64::PhysAddr;
+
+    // 4 MiB from the kernel's frame allocator, aligned so that they're a single buddy block
+    const FRAMES: usize = 1024;
+    let taken = with_kernel_paging(|_, frame_allocator| frame_allocator.allocate_contiguous(FRAMES, 4 << 20, Zone::Normal))
+        .unwrap()
+        .expect("no 4 MiB of contiguous frames");
+    let mut frames = BuddyFrameAllocator::new(phys_to_virt(PhysAddr::new(0)));
+    unsafe { frames.add_frames(taken, FRAMES) };
+    assert_eq!(frames.free_frames(), FRAMES);
+
+    // rounded up to 4 frames, aligned to 16 KiB
+    let run = frames.allocate_contiguous(3).unwrap();
+    assert_eq!(run.start_address().as_u64() % (4 * 4096), 0);
+    let single = frames.allocate_frame().unwrap();
+    assert!(single < run || single >= run + 4);
+    let huge = frames.allocate_huge_frame().unwrap();
+    assert_eq!(frames.free_frames(), FRAMES - 4 - 1 - 512);
+
+    unsafe {
+        frames.deallocate_huge_frame(huge);
+        frames.deallocate_frame(single);
+        frames.deallocate_contiguous(run, 3);
+    }
+    // everything merged back together
+    assert_eq!(frames.free_frames(), FRAMES);
+    assert!(frames.allocate_contiguous(FRAMES).is_some());
+
+    with_kernel_paging(|_, frame_allocator| unsafe { frame_allocator.deallocate_contiguous(taken, FRAMES) }).unwrap();
+}
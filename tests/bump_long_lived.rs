@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+
+// `many_boxes_long_lived` from heap_allocation.rs, which is expected to run the bump allocator out of memory:
+// apart from the most recent allocation it only reuses memory once every allocation is freed,
+// and one box here lives for the whole test.
+// Like a should_panic test, this one passes when it panics, but only for an allocation failure in the loop.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use mini_os::allocator::{self, HEAP_SIZE};
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+// set once init is done, a panic before that is a failure
+static ALLOCATING: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+    // a growing heap would just postpone running out of memory by a few hundred Kb
+    allocator::set_max_heap_size(HEAP_SIZE);
+
+    serial_print!("bump_long_lived::many_boxes_long_lived...\t");
+    let long_lived = Box::new(1);
+    ALLOCATING.store(true, Ordering::SeqCst);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        let y = Box::new(i);
+        assert_eq!(*x + *y, 2 * i);
+        drop(x); // y is the most recent allocation, so only y's memory is handed back
+    }
+    assert_eq!(*long_lived, 1);
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = MessageStart { buf: [0; 128], len: 0 };
+    let _ = write!(message, "{}", info);
+    // the alloc_error_handler panics with "Allocation error: ..."
+    let is_alloc_error = core::str::from_utf8(&message.buf[..message.len]).map_or(false, |m| m.contains("Allocation error"));
+    if ALLOCATING.load(Ordering::SeqCst) && is_alloc_error {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+// the first bytes of the panic message, the rest is dropped
+struct MessageStart {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for MessageStart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // only ASCII is expected, a character cut in half just makes from_utf8 fail
+        let count = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
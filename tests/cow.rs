@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::memory::address_space::activate_kernel;
+use mini_os::memory::cow::{self, COW};
+use mini_os::memory::{phys_to_virt, with_kernel_paging, AddressSpace};
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+// far away from anything the bootloader maps in the lower half
+const USER_ADDR: u64 = 0x7000_0000_0000;
+
+fn user_page() -> Page {
+    Page::containing_address(VirtAddr::new(USER_ADDR))
+}
+
+fn free_frames() -> usize {
+    with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap()
+}
+
+fn frame_at(space: &mut AddressSpace, addr: u64) -> PhysAddr {
+    space.translate(VirtAddr::new(addr)).unwrap().phys_addr
+}
+
+fn read_frame(addr: PhysAddr) -> u64 {
+    unsafe { *phys_to_virt(addr).as_ptr::<u64>() }
+}
+
+// writes through the page tables of `space`, so that copy-on-write faults happen
+fn write_in(space: &AddressSpace, addr: u64, value: u64) {
+    unsafe {
+        space.activate();
+        *(addr as *mut u64) = value;
+        activate_kernel();
+    }
+}
+
+#[test_case]
+fn clone_shares_frames_read_only() {
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map_user(user_page(), PageTableFlags::WRITABLE).unwrap();
+    write_in(&parent, USER_ADDR, 42);
+
+    let mut clone = parent.clone_cow().unwrap();
+    let frame = frame_at(&mut parent, USER_ADDR);
+    assert_eq!(frame_at(&mut clone, USER_ADDR), frame);
+    assert_eq!(cow::ref_count(PhysFrame::containing_address(frame)), 2);
+    for space in [&mut parent, &mut clone].iter_mut() {
+        let flags = space.translate(VirtAddr::new(USER_ADDR)).unwrap().flags;
+        assert!(flags.contains(COW));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+    }
+}
+
+#[test_case]
+fn write_copies_shared_frame() {
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map_user(user_page(), PageTableFlags::WRITABLE).unwrap();
+    write_in(&parent, USER_ADDR, 42);
+    let mut clone = parent.clone_cow().unwrap();
+    let frame = frame_at(&mut parent, USER_ADDR);
+
+    write_in(&clone, USER_ADDR, 7);
+    let copy = frame_at(&mut clone, USER_ADDR);
+    assert_ne!(copy, frame);
+    assert_eq!(read_frame(copy), 7);
+    assert_eq!(read_frame(frame), 42);
+    assert_eq!(cow::ref_count(PhysFrame::containing_address(frame)), 1);
+
+    // the parent is the last one using the frame, it just gets write access back
+    write_in(&parent, USER_ADDR, 43);
+    assert_eq!(frame_at(&mut parent, USER_ADDR), frame);
+    assert_eq!(read_frame(frame), 43);
+    let flags = parent.translate(VirtAddr::new(USER_ADDR)).unwrap().flags;
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(COW));
+}
+
+#[test_case]
+fn read_only_pages_stay_read_only() {
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map_user(user_page(), PageTableFlags::empty()).unwrap();
+    let mut clone = parent.clone_cow().unwrap();
+    let flags = clone.translate(VirtAddr::new(USER_ADDR)).unwrap().flags;
+    assert!(!flags.contains(COW));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn last_owner_frees_shared_frames() {
+    // the first address space fills in the kernel's upper half tables, those stay
+    drop(AddressSpace::new().unwrap());
+
+    let free_before = free_frames();
+    let mut parent = AddressSpace::new().unwrap();
+    for i in 0..4 {
+        parent.map_user(user_page() + i, PageTableFlags::WRITABLE).unwrap();
+    }
+    let clone = parent.clone_cow().unwrap();
+    write_in(&clone, USER_ADDR, 1);
+    drop(parent);
+    assert!(free_frames() < free_before);
+    drop(clone);
+    assert_eq!(free_frames(), free_before);
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_paging(mapper, frame_allocator);
+    // every heap page is mapped by the page fault handler
+    allocator::init_heap_lazy();
+
+    test_main();
+    loop {}
+}
+
+
+use alloc::{boxed::Box, vec::Vec};
+use mini_os::allocator::{heap_size, HEAP_MAX_SIZE};
+use mini_os::memory::{translate, vma, with_kernel_paging, Backing};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+fn free_frames() -> usize {
+    with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    with_kernel_paging(|mapper, _| translate(mapper, addr)).unwrap().is_some()
+}
+
+#[test_case]
+fn lazy_region_is_backed_on_first_touch() {
+    let start = vma::map(16 * 4096, "lazy buffer", PageTableFlags::WRITABLE, Backing::Lazy).unwrap();
+    let page = start + 5 * 4096u64;
+    assert!(!is_mapped(page));
+
+    let free_before = free_frames();
+    let ptr = (page + 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(is_mapped(page));
+    assert!(!is_mapped(page + 4096u64));
+    // one frame for the page, and maybe some for page tables
+    assert!(free_frames() < free_before && free_frames() >= free_before - 4);
+
+    let free_touched = free_frames();
+    unsafe { vma::unmap(start).unwrap() };
+    assert_eq!(free_frames(), free_touched + 1);
+}
+
+#[test_case]
+fn lazy_heap_commits_only_touched_pages() {
+    assert_eq!(heap_size(), HEAP_MAX_SIZE);
+    let free_before = free_frames();
+
+    // 8 Mb of heap, only the first and last page of it are touched
+    let mut large: Vec<u8> = Vec::with_capacity(8 * 1024 * 1024);
+    unsafe {
+        let ptr = large.as_mut_ptr();
+        ptr.write_volatile(1);
+        ptr.add(large.capacity() - 1).write_volatile(2);
+    }
+    assert!(free_before - free_frames() < 16);
+
+    let boxed = Box::new(7u64);
+    assert_eq!(*boxed, 7);
+    assert!(is_mapped(VirtAddr::new(&*boxed as *const u64 as u64)));
+}
@@ -0,0 +1,156 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::memory::with_kernel_paging;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+#[test_case]
+fn allocate_and_free_updates_counts() {
+    with_kernel_paging(|_, frame_allocator| {
+        let free_before = frame_allocator.free_frames();
+        let used_before = frame_allocator.used_frames();
+
+        let frame = frame_allocator.allocate_frame().expect("out of frames");
+        assert_eq!(frame_allocator.free_frames(), free_before - 1);
+        assert_eq!(frame_allocator.used_frames(), used_before + 1);
+
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.free_frames(), free_before);
+        assert_eq!(frame_allocator.used_frames(), used_before);
+    })
+    .expect("kernel paging not initialized");
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    with_kernel_paging(|_, frame_allocator| {
+        let first = frame_allocator.allocate_frame().unwrap();
+        let second = frame_allocator.allocate_frame().unwrap();
+        assert_ne!(first, second);
+
+        // the allocator hands out the lowest free frame above 16 MiB, so the freed one comes back first
+        unsafe { frame_allocator.deallocate_frame(first) };
+        assert_eq!(frame_allocator.allocate_frame(), Some(first));
+
+        unsafe {
+            frame_allocator.deallocate_frame(first);
+            frame_allocator.deallocate_frame(second);
+        }
+    })
+    .expect("kernel paging not initialized");
+}
+
+#[test_case]
+fn frames_are_distinct() {
+    with_kernel_paging(|_, frame_allocator| {
+        let free_before = frame_allocator.free_frames();
+        let mut frames = [None; 256];
+        for slot in frames.iter_mut() {
+            *slot = frame_allocator.allocate_frame();
+        }
+        // handed out in address order, so any frame given out twice would break strict ordering
+        for pair in frames.windows(2) {
+            assert!(pair[0].unwrap() < pair[1].unwrap());
+        }
+
+        for frame in frames.iter() {
+            unsafe { frame_allocator.deallocate_frame(frame.unwrap()) };
+        }
+        assert_eq!(frame_allocator.free_frames(), free_before);
+    })
+    .expect("kernel paging not initialized");
+}
+
+#[test_case]
+fn contiguous_frames_are_aligned_and_in_zone() {
+    use mini_os::memory::Zone;
+
+    with_kernel_paging(|_, frame_allocator| {
+        let free_before = frame_allocator.free_frames();
+        let first = frame_allocator.allocate_contiguous(16, 0x10000, Zone::Normal).expect("no 16 free frames in a row");
+        assert_eq!(first.start_address().as_u64() % 0x10000, 0);
+        // QEMU has less than 4 GiB, so it falls back to the part of Dma32 above the ISA zone
+        assert!(first.start_address().as_u64() >= Zone::IsaDma.end());
+        assert_eq!(frame_allocator.free_frames(), free_before - 16);
+        // single frames never come from the middle of the run
+        let single = frame_allocator.allocate_frame().unwrap();
+        assert!(single < first || single >= first + 16);
+
+        let isa = frame_allocator.allocate_contiguous(4, 4096, Zone::IsaDma).expect("no free frames below 16 MiB");
+        assert!(isa.start_address().as_u64() + 4 * 4096 <= Zone::IsaDma.end());
+
+        unsafe {
+            frame_allocator.deallocate_frame(single);
+            frame_allocator.deallocate_contiguous(first, 16);
+            frame_allocator.deallocate_contiguous(isa, 4);
+        }
+        assert!(frame_allocator.allocate_contiguous(0, 4096, Zone::Normal).is_none());
+        assert_eq!(frame_allocator.free_frames(), free_before);
+    })
+    .expect("kernel paging not initialized");
+}
+
+#[test_case]
+fn single_frames_avoid_isa_zone() {
+    use mini_os::memory::Zone;
+
+    with_kernel_paging(|_, frame_allocator| {
+        let isa = frame_allocator.allocate_contiguous(1, 4096, Zone::IsaDma).expect("no free frames below 16 MiB");
+        unsafe { frame_allocator.deallocate_frame(isa) };
+        // there's a free frame below 16 MiB, but also plenty above
+        let frame = frame_allocator.allocate_frame().unwrap();
+        assert!(frame.start_address().as_u64() >= Zone::IsaDma.end());
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    })
+    .expect("kernel paging not initialized");
+}
+
+#[test_case]
+fn dma_buffer_is_zeroed_and_freed() {
+    use mini_os::memory::{phys_to_virt, DmaBuffer, Zone};
+
+    let free_before = with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap();
+    let mut buffer = DmaBuffer::new(3 * 4096 + 1, 0x4000, Zone::Dma32).expect("DMA allocation failed");
+    assert_eq!(buffer.len(), 3 * 4096 + 1);
+    assert_eq!(buffer.phys_addr().as_u64() % 0x4000, 0);
+    assert!(buffer.phys_addr().as_u64() + 4 * 4096 <= Zone::Dma32.end());
+    assert_eq!(buffer.virt_addr(), phys_to_virt(buffer.phys_addr()));
+    assert!(buffer.as_slice().iter().all(|&b| b == 0));
+    buffer.as_mut_slice()[3 * 4096] = 0xab;
+
+    let used = with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap();
+    assert_eq!(used, free_before - 4);
+    drop(buffer);
+    assert_eq!(with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap(), free_before);
+}
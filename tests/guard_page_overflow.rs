@@ -0,0 +1,69 @@
+#![feature(asm)]
+#![no_std]
+#![no_main]
+
+// Overflows a guard-paged kernel stack. The page fault handler runs on its own (guarded) IST stack, since the
+// full stack has no room for its frame, and has to panic with the name of the stack.
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use mini_os::memory::KernelStack;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    serial_print!("guard_page_overflow::overflow_is_reported...\t");
+    let stack = KernelStack::new(4, "test thread").expect("mapping stack failed");
+    unsafe {
+        asm!("mov rsp, {}", "call {}", in(reg) stack.top().as_u64(), sym stack_overflow, options(noreturn));
+    }
+}
+
+#[allow(unconditional_recursion)]
+extern "C" fn stack_overflow() {
+    stack_overflow(); // keep pushing return address to the stack
+    volatile::Volatile::new(0).read(); // read something in tail position to prevent TCO
+}
+
+// keeps the start of the panic message, enough to see which stack was reported
+struct Message {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { bytes: [0; 128], len: 0 };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.bytes[..message.len]).unwrap_or("");
+    if message.contains("stack overflow in stack test thread") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
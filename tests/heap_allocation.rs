@@ -19,17 +19,18 @@ fn panic(info: &PanicInfo) -> ! {
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use mini_os::allocator;
-    use mini_os::memory::{self, BootInfoFrameAllocator};
+    use mini_os::memory::{self, BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     mini_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -46,7 +47,7 @@ fn simple_allocation() {
     assert_eq!(*heap_value_2, 13);
 }
 
-use alloc::vec::Vec;
+use alloc::{vec, vec::Vec};
 
 #[test_case]
 fn large_vec() {
@@ -60,7 +61,7 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n-1)*n/2);
 }
 
-use mini_os::allocator::HEAP_SIZE;
+use mini_os::allocator::{self, HEAP_SIZE};
 
 #[test_case]
 fn many_boxes() {
@@ -72,6 +73,8 @@ fn many_boxes() {
 }
 
 
+// expected to fail with the bump allocator, tests/bump_long_lived.rs checks that it does
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     //this will break Bump Allocator, since it can't dealloc if something is left on the heap
@@ -83,3 +86,87 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+
+#[test_case]
+fn large_allocation_after_fragmentation() {
+    // fill half the heap with interleaved blocks too large for any fixed size block list, then free them all.
+    // Without merging of freed regions, the heap ends up split into pieces too small for the final Vec
+    let heap_size = allocator::heap_size();
+    // growing the heap would make room for the Vec without any merging
+    allocator::set_max_heap_size(heap_size);
+    let mut blocks = Vec::with_capacity(16);
+    for i in 0..8 {
+        blocks.push(vec![i as u8; 4096]);
+        blocks.push(vec![i as u8; 2500]);
+    }
+    drop(blocks);
+
+    let big: Vec<u8> = vec![1; HEAP_SIZE * 3 / 5];
+    assert_eq!(big.len(), big.iter().map(|&b| b as usize).sum::<usize>());
+    assert_eq!(allocator::heap_size(), heap_size);
+    drop(big);
+    allocator::set_max_heap_size(allocator::HEAP_MAX_SIZE);
+}
+
+
+// the linked_list_allocator crate backend doesn't grow
+#[cfg(not(feature = "alloc-external"))]
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let initial_size = allocator::heap_size();
+    let n = HEAP_SIZE; // a Vec<u64> of HEAP_SIZE elements is 8 times the initial heap
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i as u64);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), ((n - 1) * n / 2) as u64);
+    assert!(allocator::heap_size() > initial_size);
+    assert!(allocator::heap_size() <= allocator::HEAP_MAX_SIZE);
+}
+
+
+// the linked_list_allocator crate backend doesn't count allocations
+#[cfg(not(feature = "alloc-external"))]
+#[test_case]
+fn stats_track_allocations() {
+    let before = allocator::heap_stats();
+    let x = Box::new([0u8; 100]);
+    let during = allocator::heap_stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 100);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+
+    drop(x);
+    let after = allocator::heap_stats();
+    assert_eq!(after.frees, before.frees + 1);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    if let Some(largest_free_block) = after.largest_free_block {
+        assert!(largest_free_block <= after.free_bytes);
+    }
+    assert!(after.free_bytes <= after.heap_size);
+}
+
+
+#[test_case]
+fn slab_cache_reuses_objects() {
+    use mini_os::allocator::slab::SlabCache;
+
+    struct Task {
+        id: usize,
+        stack_pointer: usize,
+    }
+    static TASKS: SlabCache<Task> = SlabCache::new();
+
+    let tasks: Vec<_> = (0..100).map(|id| TASKS.alloc(Task { id, stack_pointer: id * 8 })).collect();
+    assert!(tasks.iter().enumerate().all(|(i, task)| task.id == i && task.stack_pointer == i * 8));
+    let slabs = TASKS.slabs();
+    drop(tasks);
+    assert_eq!(TASKS.objects_in_use(), 0);
+
+    // all objects are free again, so these fit in the slabs from before
+    let tasks: Vec<_> = (0..100).map(|id| TASKS.alloc(Task { id, stack_pointer: 0 })).collect();
+    assert!(TASKS.slabs() <= slabs);
+    drop(tasks);
+    TASKS.shrink();
+}
@@ -0,0 +1,94 @@
+#![feature(abi_x86_interrupt)]
+#![no_std]
+#![no_main]
+
+// Jumps to a `ret` instruction on the heap, which is mapped NO_EXECUTE. The instruction fetch has to
+// page fault, the test page fault handler checks that it's the one and then jumps to the same bytes through
+// the bootloader's mapping of physical memory, which has to fault as well.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::mem;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// where the code was put, for the page fault handler
+static CODE_ADDR: AtomicU64 = AtomicU64::new(0);
+// the same code in the physical memory mapping
+static PHYS_ALIAS_ADDR: AtomicU64 = AtomicU64::new(0);
+static HEAP_FAULTED: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, phys_to_virt, translate, BitmapFrameAllocator};
+
+    serial_print!("heap_exec_faults::heap_exec_faults...\t");
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    // the test IDT has no handlers for the timer and keyboard
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let code = Box::new([0xc3u8; 16]); // ret
+    CODE_ADDR.store(code.as_ptr() as u64, Ordering::SeqCst);
+    let phys_addr = translate(&mapper, VirtAddr::from_ptr(code.as_ptr())).unwrap().phys_addr;
+    PHYS_ALIAS_ADDR.store(phys_to_virt(phys_addr).as_u64(), Ordering::SeqCst);
+    jump_to(code.as_ptr() as u64);
+
+    panic!("Execution continued after jumping into the heap");
+}
+
+fn jump_to(addr: u64) {
+    let function: extern "C" fn() = unsafe { mem::transmute(addr) };
+    function();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let fetch_from_present_page = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH;
+    let expected = if HEAP_FAULTED.load(Ordering::SeqCst) { &PHYS_ALIAS_ADDR } else { &CODE_ADDR };
+    if Cr2::read().as_u64() != expected.load(Ordering::SeqCst) || !error_code.contains(fetch_from_present_page) {
+        serial_println!("[failed]\nunexpected page fault at {:?}: {:?}", Cr2::read(), error_code);
+        exit_qemu(QemuExitCode::Failed);
+    } else if !HEAP_FAULTED.swap(true, Ordering::SeqCst) {
+        // a page fault in the page fault handler is just another page fault, it comes back here
+        jump_to(PHYS_ALIAS_ADDR.load(Ordering::SeqCst));
+        serial_println!("[failed]\nexecution continued after jumping into the physical memory mapping");
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap_with_huge_pages(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use alloc::{boxed::Box, vec::Vec};
+use mini_os::allocator::{heap_size, HEAP_START};
+use mini_os::memory::{translate, with_kernel_paging};
+use x86_64::{
+    structures::paging::{PageSize, Size2MiB},
+    VirtAddr,
+};
+
+#[test_case]
+fn heap_starts_with_one_huge_page() {
+    let value = Box::new(42u64);
+    let addr = VirtAddr::new(&*value as *const u64 as u64);
+    let translation = with_kernel_paging(|mapper, _| translate(mapper, addr)).unwrap().unwrap();
+    assert_eq!(translation.page_size, Size2MiB::SIZE);
+    assert!(heap_size() >= Size2MiB::SIZE as usize);
+}
+
+#[test_case]
+fn heap_grows_by_huge_pages() {
+    // doesn't fit into the first 2 Mb page
+    let large: Vec<u8> = Vec::with_capacity(3 * 1024 * 1024);
+    assert_eq!(heap_size() % Size2MiB::SIZE as usize, 0);
+    assert!(heap_size() >= 4 * 1024 * 1024);
+
+    let end = VirtAddr::new(large.as_ptr() as u64 + large.capacity() as u64 - 1);
+    let translation = with_kernel_paging(|mapper, _| translate(mapper, end)).unwrap().unwrap();
+    assert_eq!(translation.page_size, Size2MiB::SIZE);
+    assert!(end.as_u64() >= HEAP_START as u64 + Size2MiB::SIZE);
+}
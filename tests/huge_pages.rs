@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::SeqCst);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::memory::{translate, vma, with_kernel_paging, HugeFrameAllocator, HugePageBuffer, Zone};
+use x86_64::{
+    structures::paging::{PageSize, Size2MiB},
+    PhysAddr, VirtAddr,
+};
+
+// unused by anything else
+const BUFFER_START: u64 = 0x_5555_5540_0000;
+
+#[test_case]
+fn translates_bootloader_huge_pages() {
+    // the bootloader maps all of physical memory with 2 Mb pages
+    let addr = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::SeqCst) + 0x20_1234);
+    let translation = with_kernel_paging(|mapper, _| translate(mapper, addr)).unwrap();
+    let translation = translation.expect("physical memory isn't mapped");
+    assert_eq!(translation.phys_addr, PhysAddr::new(0x20_1234));
+    assert_eq!(translation.page_size, Size2MiB::SIZE);
+}
+
+#[test_case]
+fn unmapped_address_translates_to_none() {
+    let addr = VirtAddr::new(BUFFER_START);
+    assert!(with_kernel_paging(|mapper, _| translate(mapper, addr)).unwrap().is_none());
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    with_kernel_paging(|_, frame_allocator| {
+        let free_before = frame_allocator.free_frames();
+        let first = frame_allocator.allocate_huge_frame().expect("no free 2 Mb frame");
+        let second = frame_allocator.allocate_huge_frame().expect("no free 2 Mb frame");
+        assert_ne!(first, second);
+        assert!(first.start_address().is_aligned(Size2MiB::SIZE));
+        assert!(second.start_address().is_aligned(Size2MiB::SIZE));
+        // kept for ISA DMA while there are free 2 MiB frames above 16 MiB
+        assert!(first.start_address().as_u64() >= Zone::IsaDma.end());
+        assert_eq!(frame_allocator.free_frames(), free_before - 2 * 512);
+
+        unsafe {
+            frame_allocator.deallocate_huge_frame(first);
+            frame_allocator.deallocate_huge_frame(second);
+        }
+        assert_eq!(frame_allocator.free_frames(), free_before);
+    })
+    .expect("kernel paging not initialized");
+}
+
+#[test_case]
+fn huge_page_buffer_is_mapped_with_2mib_pages() {
+    let free_frames = || with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap();
+    let free_before = free_frames();
+
+    let mut buffer = HugePageBuffer::new(2).expect("mapping huge pages failed");
+    assert_eq!(buffer.len(), 4 * 1024 * 1024);
+    assert!(buffer.start().is_aligned(Size2MiB::SIZE));
+    assert_eq!(vma::find(buffer.start()).map(|vma| vma.size), Some(buffer.len() as u64));
+    let slice = buffer.as_mut_slice();
+    slice[0] = 1;
+    let last = slice.len() - 1;
+    slice[last] = 2;
+    assert_eq!(buffer.as_slice()[0] + buffer.as_slice()[last], 3);
+
+    let end = buffer.start() + (buffer.len() as u64 - 1);
+    let translation = with_kernel_paging(|mapper, _| translate(mapper, end)).unwrap().unwrap();
+    assert_eq!(translation.page_size, Size2MiB::SIZE);
+    assert!(free_frames() <= free_before - 2 * 512);
+
+    let start = buffer.start();
+    drop(buffer);
+    assert!(with_kernel_paging(|mapper, _| translate(mapper, end)).unwrap().is_none());
+    assert!(vma::find(start).is_none());
+    // page tables created for the buffer stay around
+    assert!(free_frames() >= free_before - 3);
+}
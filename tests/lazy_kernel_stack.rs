@@ -0,0 +1,83 @@
+#![feature(asm)]
+#![no_std]
+#![no_main]
+
+// Runs on a lazy kernel stack, whose pages only get mapped when they're first touched. Pushing onto a new page
+// page faults, and the page fault handler has to run on its own IST stack: there's no room for its frame on
+// the faulting one, without the IST it would double fault.
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use mini_os::memory::{translate, with_kernel_paging, KernelStack};
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const STACK_PAGES: u64 = 8;
+
+static STACK_BOTTOM: AtomicU64 = AtomicU64::new(0);
+static STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory::{self, BitmapFrameAllocator};
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    serial_print!("lazy_kernel_stack::runs_on_lazy_stack...\t");
+    let stack = KernelStack::lazy(STACK_PAGES, "lazy test stack").expect("mapping stack failed");
+    assert!(!is_mapped(stack.top() - 1u64), "lazy stack is mapped up front");
+    STACK_BOTTOM.store(stack.bottom().as_u64(), Ordering::SeqCst);
+    STACK_TOP.store(stack.top().as_u64(), Ordering::SeqCst);
+    // the test ends on the lazy stack, it's never switched back
+    let top = stack.leak();
+    unsafe {
+        asm!("mov rsp, {}", "call {}", in(reg) top.as_u64(), sym on_lazy_stack, options(noreturn));
+    }
+}
+
+extern "C" fn on_lazy_stack() -> ! {
+    // about 4 KiB per call, so it runs over a few pages of the stack
+    assert_eq!(use_stack(3), 3 * 4096);
+
+    let top = VirtAddr::new(STACK_TOP.load(Ordering::SeqCst));
+    assert!(is_mapped(top - 1u64));
+    assert!(is_mapped(top - 3 * 4096u64));
+    // the bottom page was never touched
+    assert!(!is_mapped(VirtAddr::new(STACK_BOTTOM.load(Ordering::SeqCst))));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+fn use_stack(depth: u64) -> u64 {
+    let mut buffer = [0u8; 4096];
+    // volatile, so that the buffer really ends up on the stack
+    for byte in buffer.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 1) };
+    }
+    let sum = buffer.iter().map(|byte| unsafe { ptr::read_volatile(byte) } as u64).sum::<u64>();
+    if depth == 1 {
+        sum
+    } else {
+        sum + use_stack(depth - 1)
+    }
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    with_kernel_paging(|mapper, _| translate(mapper, addr)).unwrap().is_some()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+// Runs a test that frees everything it allocates, then one that leaks a Box through test_runner's
+// Testable::run. With alloc-tracking the leak check has to fail the second one.
+// Like a should_panic test, this one passes when it panics, but only if the panic comes from the leak
+// check after leaks_a_box returned.
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use mini_os::{exit_qemu, serial_println, QemuExitCode, Testable};
+
+entry_point!(main);
+
+static FREES_EVERYTHING_PASSED: AtomicBool = AtomicBool::new(false);
+static LEAKED: AtomicBool = AtomicBool::new(false);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    // must not be reported
+    frees_everything.run();
+    FREES_EVERYTHING_PASSED.store(true, Ordering::SeqCst);
+    leaks_a_box.run();
+
+    serial_println!("[leak not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn frees_everything() {
+    let v: Vec<u64> = (0..100).collect();
+    assert_eq!(v.iter().sum::<u64>(), 4950);
+}
+
+fn leaks_a_box() {
+    Box::leak(Box::new(42u64));
+    LEAKED.store(true, Ordering::SeqCst);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if FREES_EVERYTHING_PASSED.load(Ordering::SeqCst) && LEAKED.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
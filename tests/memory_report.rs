@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Once;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+// for the tests that look at the memory map
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    BOOT_INFO.call_once(|| boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use alloc::vec::Vec;
+use mini_os::allocator::heap_size;
+use mini_os::memory::report::{self, frame_count, BootMemory, Purpose};
+use mini_os::memory::{with_kernel_paging, AddressSpace, DmaBuffer, KernelStack, Zone};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+#[test_case]
+fn boot_memory_adds_up() {
+    let memory = BootMemory::from_memory_map(&BOOT_INFO.wait().unwrap().memory_map);
+    let usable_frames = with_kernel_paging(|_, frame_allocator| frame_allocator.usable_frames()).unwrap();
+    assert_eq!(memory.usable, usable_frames as u64 * 4096);
+    assert!(memory.kernel > 0);
+    assert!(memory.page_tables > 0);
+    assert!(memory.usable + memory.kernel + memory.page_tables + memory.bootloader <= memory.total);
+}
+
+#[test_case]
+fn reports_print() {
+    report::print_boot_memory(&BOOT_INFO.wait().unwrap().memory_map);
+    report::print_frame_counts();
+}
+
+// the external allocator never grows the heap
+#[cfg(not(feature = "alloc-external"))]
+#[test_case]
+fn heap_growth_is_counted() {
+    let before = frame_count(Purpose::Heap);
+    assert!(before.in_use() * 4096 >= heap_size() as u64);
+    // more than is mapped, so the heap has to grow
+    let vec: Vec<u8> = Vec::with_capacity(heap_size() + 1);
+    assert!(frame_count(Purpose::Heap).allocated > before.allocated);
+    drop(vec);
+}
+
+#[test_case]
+fn kernel_stack_frames_are_counted() {
+    let before = frame_count(Purpose::Stacks);
+    let stack = KernelStack::new(4, "counted stack").unwrap();
+    assert_eq!(frame_count(Purpose::Stacks).in_use(), before.in_use() + 4);
+    drop(stack);
+    assert_eq!(frame_count(Purpose::Stacks).in_use(), before.in_use());
+}
+
+#[test_case]
+fn dma_frames_are_counted() {
+    let before = frame_count(Purpose::Dma);
+    let buffer = DmaBuffer::new(3 * 4096, 4096, Zone::Normal).unwrap();
+    assert_eq!(frame_count(Purpose::Dma).in_use(), before.in_use() + 3);
+    drop(buffer);
+    assert_eq!(frame_count(Purpose::Dma), report::FrameCount { allocated: before.allocated + 3, freed: before.freed + 3 });
+}
+
+#[test_case]
+fn address_space_tables_are_counted() {
+    // the first address space gives the kernel's upper half its level 3 tables, those stay
+    drop(AddressSpace::new().unwrap());
+
+    let before = frame_count(Purpose::PageTables).in_use();
+    let mut space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(0x7000_0000_0000));
+    space.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    // level 4, 3, 2 and 1 table
+    assert_eq!(frame_count(Purpose::PageTables).in_use(), before + 4);
+    drop(space);
+    assert_eq!(frame_count(Purpose::PageTables).in_use(), before);
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::memory::{map_mmio, phys_to_virt, translate, with_kernel_paging, VmaError};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageTableFlags};
+use x86_64::PhysAddr;
+
+// second line of the VGA text buffer, which doesn't start on a page boundary
+const VGA_LINE: u64 = 0xb8000 + 160;
+
+fn free_frames() -> usize {
+    with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn mmio_region_is_uncached() {
+    let region = map_mmio(PhysAddr::new(VGA_LINE), 160).unwrap();
+    assert_eq!(region.virt_addr().as_u64() % 4096, 160);
+    let translation = with_kernel_paging(|mapper, _| translate(mapper, region.virt_addr())).unwrap().unwrap();
+    assert_eq!(translation.phys_addr, PhysAddr::new(VGA_LINE));
+    assert!(translation.flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+}
+
+#[test_case]
+fn mmio_accessors_reach_the_device() {
+    let mut region = map_mmio(PhysAddr::new(VGA_LINE), 160).unwrap();
+    // white on black 'A' in the last column
+    region.write::<u16>(158, 0x0f41);
+    let through_physical_mapping = unsafe { *phys_to_virt(PhysAddr::new(VGA_LINE + 158)).as_ptr::<u16>() };
+    assert_eq!(through_physical_mapping, 0x0f41);
+    assert_eq!(region.read::<u16>(158), 0x0f41);
+    assert_eq!(region.read::<u8>(159), 0x0f);
+}
+
+#[test_case]
+fn dropping_mmio_region_keeps_frames() {
+    let region = map_mmio(PhysAddr::new(0xb8000), 2 * 4096).unwrap();
+    let start = region.virt_addr();
+    let free_mapped = free_frames();
+    drop(region);
+    assert!(with_kernel_paging(|mapper, _| translate(mapper, start)).unwrap().is_none());
+    // device memory isn't ours, so it doesn't go to the frame allocator
+    assert_eq!(free_frames(), free_mapped);
+}
+
+#[test_case]
+fn usable_ram_is_not_mapped_as_mmio() {
+    let frame = with_kernel_paging(|_, frame_allocator| frame_allocator.allocate_frame()).unwrap().unwrap();
+    // also for a range that only partly covers it
+    let before = frame.start_address() - 8u64;
+    for &(addr, len) in [(frame.start_address(), 4096), (before, 16)].iter() {
+        match map_mmio(addr, len) {
+            Err(VmaError::NotDeviceMemory(start)) => assert_eq!(start, addr),
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("mapped usable RAM at {:?} as mmio", addr),
+        }
+    }
+    with_kernel_paging(|_, frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) }).unwrap();
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Runs the heap out of memory with a reclaim callback registered, the backends in
+// src/allocator/ have to call it and retry (alloc-external doesn't, so this test doesn't pass with it)
+
+extern crate alloc;
+
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mini_os::allocator::{
+    self,
+    oom::{self, OomPolicy},
+};
+use spin::Mutex;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+    // no growing, the heap has to run out
+    allocator::set_max_heap_size(allocator::HEAP_SIZE);
+
+    test_main();
+    loop {}
+}
+
+// more than half of the heap, so that two of these never fit at once
+const LARGE: usize = 60 * 1024;
+
+// stands in for a cache the kernel could drop when memory gets tight
+static CACHE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+static RECLAIM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn drop_cache(_layout: &Layout) -> bool {
+    RECLAIM_CALLS.fetch_add(1, Ordering::SeqCst);
+    CACHE.lock().take().is_some()
+}
+
+fn nothing_to_drop(_layout: &Layout) -> bool {
+    RECLAIM_CALLS.fetch_add(1, Ordering::SeqCst);
+    false
+}
+
+#[test_case]
+fn allocation_succeeds_after_reclaim() {
+    RECLAIM_CALLS.store(0, Ordering::SeqCst);
+    *CACHE.lock() = Some(Vec::with_capacity(LARGE));
+    oom::set_oom_policy(OomPolicy::Reclaim(drop_cache));
+
+    let large: Vec<u8> = Vec::with_capacity(LARGE);
+    assert!(CACHE.lock().is_none());
+    assert_eq!(RECLAIM_CALLS.load(Ordering::SeqCst), 1);
+    drop(large);
+    oom::set_oom_policy(OomPolicy::Halt);
+}
+
+#[test_case]
+fn allocation_fails_when_nothing_is_reclaimed() {
+    RECLAIM_CALLS.store(0, Ordering::SeqCst);
+    oom::set_oom_policy(OomPolicy::Reclaim(nothing_to_drop));
+
+    // straight through the global allocator, so that the null pointer comes back instead of ending in alloc_error_handler
+    let layout = Layout::from_size_align(2 * LARGE, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(ptr.is_null());
+    assert_eq!(RECLAIM_CALLS.load(Ordering::SeqCst), 1);
+    oom::set_oom_policy(OomPolicy::Halt);
+}
@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::SeqCst);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use alloc::vec::Vec;
+use mini_os::allocator::HEAP_START;
+use mini_os::memory::dump::{self, MappedRange};
+use mini_os::memory::{translate, vma, with_kernel_paging, Backing};
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+fn active_ranges() -> Vec<MappedRange> {
+    dump::ranges(dump::active_level_4_frame())
+}
+
+fn range_of(ranges: &[MappedRange], addr: VirtAddr) -> Option<MappedRange> {
+    ranges.iter().find(|range| range.contains(addr)).copied()
+}
+
+#[test_case]
+fn ranges_are_sorted_and_merged() {
+    let ranges = active_ranges();
+    assert!(!ranges.is_empty());
+    for pair in ranges.windows(2) {
+        let end = pair[0].virt_start.as_u64() + pair[0].size;
+        assert!(end <= pair[1].virt_start.as_u64());
+        // ranges that continue each other end up as one
+        let continues = end == pair[1].virt_start.as_u64()
+            && pair[0].phys_start + pair[0].size == pair[1].phys_start
+            && pair[0].flags == pair[1].flags;
+        assert!(!continues);
+    }
+}
+
+#[test_case]
+fn ranges_agree_with_translate() {
+    let ranges = active_ranges();
+    let offset = PHYS_MEM_OFFSET.load(Ordering::SeqCst);
+    let addrs = [HEAP_START as u64, 0xb8000, offset + 0x1000, main as *const () as u64];
+    for &addr in addrs.iter() {
+        let addr = VirtAddr::new(addr);
+        let translation = with_kernel_paging(|mapper, _| translate(mapper, addr)).unwrap().unwrap();
+        let range = range_of(&ranges, addr).expect("mapped address missing from dump");
+        assert_eq!(range.translate(addr), Some(translation.phys_addr));
+        assert_eq!(range.flags.contains(PageTableFlags::HUGE_PAGE), translation.page_size != 4096);
+    }
+}
+
+#[test_case]
+fn heap_is_writable_kernel_memory() {
+    let heap = range_of(&active_ranges(), VirtAddr::new(HEAP_START as u64)).unwrap();
+    assert!(heap.flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+    assert!(!heap.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn boot_stack_is_no_execute() {
+    let on_stack = 0u8;
+    let stack = range_of(&active_ranges(), VirtAddr::from_ptr(&on_stack)).unwrap();
+    assert!(stack.flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn physical_memory_mapping_is_no_execute() {
+    let physical_memory = range_of(&active_ranges(), VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::SeqCst))).unwrap();
+    assert!(physical_memory.flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn new_mapping_shows_up() {
+    let start = vma::map(2 * 4096, "dumped", PageTableFlags::WRITABLE, Backing::Fixed(PhysAddr::new(0xb8000))).unwrap();
+    let range = range_of(&active_ranges(), start).unwrap();
+    assert_eq!(range.translate(start + 4096u64), Some(PhysAddr::new(0xb9000)));
+    unsafe { vma::unmap(start).unwrap() };
+    assert!(range_of(&active_ranges(), start).is_none());
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+// PIC masks before init_apic_with, which has to restore them when it fails
+static PIC_MASKS: AtomicU16 = AtomicU16::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+    // QEMU's default machine has an APIC, pretend CPUID said otherwise
+    PIC_MASKS.store(pic_masks(), Ordering::SeqCst);
+    match interrupts::init_apic_with(false) {
+        Err(ApicError::NotSupported) => {}
+        other => panic!("init_apic_with(false) returned {:?}", other),
+    }
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::interrupts::{self, ApicError, Controller};
+use x86_64::instructions::port::Port;
+
+// both PICs' masks, the second one in the high byte
+fn pic_masks() -> u16 {
+    let masks: [u8; 2] = unsafe { [Port::new(0x21).read(), Port::new(0xa1).read()] };
+    u16::from_le_bytes(masks)
+}
+
+#[test_case]
+fn pic_stays_in_use() {
+    assert_eq!(interrupts::controller(), Controller::Pic);
+}
+
+#[test_case]
+fn pic_masks_are_restored() {
+    assert_eq!(pic_masks(), PIC_MASKS.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    let start = interrupts::ticks();
+    while interrupts::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+}
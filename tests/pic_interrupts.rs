@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// interrupts through the legacy PIC, what the kernel falls back to without an APIC
+
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    mini_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+
+use mini_os::interrupts::{self, Controller};
+
+#[test_case]
+fn pic_is_in_use() {
+    assert_eq!(interrupts::controller(), Controller::Pic);
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    let start = interrupts::ticks();
+    // every tick gets an EOI, or the next one would never come
+    while interrupts::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+}
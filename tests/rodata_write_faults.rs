@@ -0,0 +1,65 @@
+#![feature(abi_x86_interrupt)]
+#![no_std]
+#![no_main]
+
+// Writes to a constant in .rodata, which memory::init maps read-only. The write has to page fault,
+// the test page fault handler checks that it's the one.
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::ptr;
+use lazy_static::lazy_static;
+use mini_os::memory::{self, Section};
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static CONSTANT: u64 = 42;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("rodata_write_faults::rodata_write_faults...\t");
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    // the test IDT has no handlers for the timer and keyboard
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let addr = VirtAddr::from_ptr(&CONSTANT);
+    assert_eq!(Section::containing(addr), Some(Section::Rodata));
+    unsafe { ptr::write_volatile(addr.as_mut_ptr::<u64>(), 0) };
+
+    panic!("Execution continued after writing to .rodata");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let write_to_present_page = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if Cr2::read() == VirtAddr::from_ptr(&CONSTANT) && error_code.contains(write_to_present_page) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\nunexpected page fault at {:?}: {:?}", Cr2::read(), error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_paging(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+
+use mini_os::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use mini_os::memory::vma::{self, VmaManager, MAX_VMAS};
+use mini_os::memory::{translate, with_kernel_paging, Backing, Vma, VmaError};
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+const WRITABLE: PageTableFlags = PageTableFlags::WRITABLE;
+
+fn free_frames() -> usize {
+    with_kernel_paging(|_, frame_allocator| frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn ranges_never_overlap() {
+    let mut vmas = VmaManager::new(0x10_0000, 0x20_0000);
+    let first = vmas.allocate(0x3000, 0, "first", WRITABLE, Backing::Anonymous).unwrap();
+    let second = vmas.allocate(0x1000, 0x10000, "second", WRITABLE, Backing::Anonymous).unwrap();
+    assert_eq!(first.start.as_u64(), 0x10_0000);
+    assert_eq!(second.start.as_u64(), 0x11_0000);
+
+    // the gap in between is used before anything past the last region
+    let third = vmas.allocate(0x2000, 0, "third", WRITABLE, Backing::Anonymous).unwrap();
+    assert_eq!(third.start, first.end());
+    assert_eq!(vmas.find(VirtAddr::new(0x10_4fff)).unwrap().purpose, "third");
+    assert!(vmas.find(VirtAddr::new(0x10_5000)).is_none());
+
+    let overlapping = Vma { start: VirtAddr::new(0x10_2000), ..first };
+    assert!(matches!(vmas.reserve_at(overlapping), Err(VmaError::Overlaps(start)) if start == first.start));
+    assert!(matches!(
+        vmas.allocate(0x10_0000, 0, "too large", WRITABLE, Backing::Anonymous),
+        Err(VmaError::OutOfAddressSpace)
+    ));
+
+    assert_eq!(vmas.remove(first.start).unwrap().purpose, "first");
+    let starts = [third.start, second.start];
+    assert!(vmas.iter().map(|vma| vma.start).eq(starts.iter().copied()));
+}
+
+#[test_case]
+fn manager_holds_a_limited_number_of_regions() {
+    let mut vmas = VmaManager::new(0x10_0000, 0x1000_0000);
+    for _ in 0..MAX_VMAS {
+        vmas.allocate(0x1000, 0, "page", WRITABLE, Backing::Anonymous).unwrap();
+    }
+    assert!(matches!(
+        vmas.allocate(0x1000, 0, "one too many", WRITABLE, Backing::Anonymous),
+        Err(VmaError::TooManyRegions)
+    ));
+}
+
+#[test_case]
+fn heap_range_is_reserved() {
+    let heap = vma::find(VirtAddr::new(HEAP_START as u64)).expect("heap isn't registered");
+    assert_eq!(heap.purpose, "kernel heap");
+    assert_eq!(heap.size, HEAP_MAX_SIZE as u64);
+    let inside_heap = Vma { start: VirtAddr::new(HEAP_START as u64 + 0x1000), size: 0x1000, ..heap };
+    assert!(vma::reserve_at(inside_heap).is_err());
+}
+
+#[test_case]
+fn anonymous_mapping_is_zeroed_and_freed() {
+    let start = vma::map(3 * 4096, "test buffer", WRITABLE, Backing::Anonymous).unwrap();
+    // counted after mapping: page tables created on the way stay around after the unmap
+    let free_mapped = free_frames();
+    assert!(start.as_u64() >= vma::KERNEL_VMA_START);
+    assert_eq!(vma::find(start + 4096u64).unwrap().purpose, "test buffer");
+
+    let buffer = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u8>(), 3 * 4096) };
+    assert!(buffer.iter().all(|&b| b == 0));
+    buffer[3 * 4096 - 1] = 42;
+
+    unsafe { vma::unmap(start).unwrap() };
+    assert!(vma::find(start).is_none());
+    assert!(with_kernel_paging(|mapper, _| translate(mapper, start)).unwrap().is_none());
+    assert_eq!(free_frames(), free_mapped + 3);
+}
+
+#[test_case]
+fn fixed_mapping_points_at_its_frames() {
+    // the VGA text buffer, mapped a second time
+    let vga = PhysAddr::new(0xb8000);
+    let start = vma::map(4096, "vga buffer", WRITABLE, Backing::Fixed(vga)).unwrap();
+    let translation = with_kernel_paging(|mapper, _| translate(mapper, start + 8u64)).unwrap().unwrap();
+    assert_eq!(translation.phys_addr, vga + 8u64);
+
+    let free_mapped = free_frames();
+    unsafe { vma::unmap(start).unwrap() };
+    // the frame isn't ours, so it doesn't go to the frame allocator
+    assert_eq!(free_frames(), free_mapped);
+}
+
+#[test_case]
+fn mmio_mapping_is_uncached() {
+    let start = vma::map(4096, "mmio", WRITABLE, Backing::Mmio(PhysAddr::new(0xb8000))).unwrap();
+    let translation = with_kernel_paging(|mapper, _| translate(mapper, start)).unwrap().unwrap();
+    assert!(translation.flags.contains(PageTableFlags::NO_CACHE));
+    unsafe { vma::unmap(start).unwrap() };
+
+    assert!(matches!(
+        vma::map(4096, "unaligned", WRITABLE, Backing::Mmio(PhysAddr::new(0xb8010))),
+        Err(VmaError::NotAligned)
+    ));
+}
+
+#[test_case]
+fn kernel_stack_has_unmapped_guard_page() {
+    use mini_os::memory::KernelStack;
+
+    let stack = KernelStack::new(4, "test stack").unwrap();
+    assert_eq!(stack.top() - stack.bottom(), 4 * 4096);
+    assert!(with_kernel_paging(|mapper, _| translate(mapper, stack.top() - 8u64)).unwrap().is_some());
+    assert!(with_kernel_paging(|mapper, _| translate(mapper, stack.guard_page())).unwrap().is_none());
+    assert_eq!(vma::guard_page_owner(stack.guard_page() + 100u64), Some("test stack"));
+    assert_eq!(vma::guard_page_owner(stack.bottom()), None);
+
+    let guard = stack.guard_page();
+    drop(stack);
+    assert!(vma::find(guard).is_none());
+    assert!(vma::find(guard + 4096u64).is_none());
+}
+
+#[test_case]
+fn ist_stacks_are_guarded() {
+    let mut ist_stacks = 0;
+    vma::for_each(|vma| {
+        if vma.purpose == "double fault IST" && vma.backing == Backing::Guard {
+            ist_stacks += 1;
+        }
+    });
+    assert_eq!(ist_stacks, 1);
+}